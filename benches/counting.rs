@@ -0,0 +1,105 @@
+//! Compares counting via materialized `SearchMatch` items with the lean `ReadFilter::count_into`
+//!
+//! The number of synthetic reads defaults to 100k, set `READ_FILTER_BENCH_READS=10000000` for a full sized run.
+use bio::alphabets::dna;
+use bio::io::fastq;
+use counter::Counter;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use read_filter::config::{FilterBuilder, FilterParams};
+use read_filter::matching::ReadFilter;
+use read_filter::stat::RunningStats;
+
+const LEFT_FLANK: &str = "AGAGAGGC";
+const RIGHT_FLANK: &str = "GCCCAGGC";
+const INSERT_LEN: usize = 21;
+const EXPECT_BEGIN: usize = 36;
+const READ_LEN: usize = 150;
+
+fn bench_config() -> FilterParams {
+    FilterBuilder::new(LEFT_FLANK, RIGHT_FLANK, INSERT_LEN as u32)
+        .window(EXPECT_BEGIN as u32, 8)
+        .min_peak_qual(20)
+        .min_mean_qual(30)
+        .build()
+        .unwrap()
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+fn rand_seq(len: usize, state: &mut u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| b"ACGT"[(xorshift(state) % 4) as usize])
+        .collect()
+}
+
+/// Deterministic pseudo random amplicon reads with a limited number of distinct inserts, half of them reversed
+fn synthetic_fastq(n_reads: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_F491_4F6C_DD1D;
+    let variants: Vec<Vec<u8>> = (0..1000)
+        .map(|_| rand_seq(INSERT_LEN, &mut state))
+        .collect();
+    let mut out = Vec::with_capacity(n_reads * (2 * READ_LEN + 20));
+    for i in 0..n_reads {
+        let mut read = rand_seq(EXPECT_BEGIN, &mut state);
+        read.extend_from_slice(LEFT_FLANK.as_bytes());
+        read.extend_from_slice(&variants[(xorshift(&mut state) % 1000) as usize]);
+        read.extend_from_slice(RIGHT_FLANK.as_bytes());
+        let rest = READ_LEN - read.len();
+        read.extend(rand_seq(rest, &mut state));
+        if i % 2 == 1 {
+            read = dna::revcomp(&read);
+        }
+        out.extend_from_slice(format!("@read_{}\n", i).as_bytes());
+        out.extend_from_slice(&read);
+        out.extend_from_slice(b"\n+\n");
+        out.resize(out.len() + READ_LEN, b'I');
+        out.push(b'\n');
+    }
+    out
+}
+
+fn counting(c: &mut Criterion) {
+    let n_reads = std::env::var("READ_FILTER_BENCH_READS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(100_000);
+    let data = synthetic_fastq(n_reads);
+    let cfg = bench_config();
+
+    let mut group = c.benchmark_group("counting");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(n_reads as u64));
+    group.bench_function("materialized", |b| {
+        b.iter_batched(
+            RunningStats::default,
+            |mut stats| {
+                let records = fastq::Reader::new(&data[..]).records();
+                ReadFilter::new(records, &cfg, &mut stats)
+                    .map(|a| a.seq)
+                    .collect::<Counter<Vec<u8>>>()
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("count_into", |b| {
+        b.iter_batched(
+            RunningStats::default,
+            |mut stats| {
+                let records = fastq::Reader::new(&data[..]).records();
+                let mut counter = Counter::new();
+                ReadFilter::new(records, &cfg, &mut stats).count_into(&mut counter);
+                counter
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, counting);
+criterion_main!(benches);
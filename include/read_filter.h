@@ -0,0 +1,149 @@
+#ifndef READ_FILTER_H
+#define READ_FILTER_H
+
+/* Generated with cbindgen from src/capi.rs, do not edit manually */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Outcome of `rf_filter_read`
+ */
+typedef enum RfStatus {
+  /**
+   * Insert extracted and written to the `RfMatch`
+   */
+  RF_ACCEPTED = 0,
+  /**
+   * Flanks not found at the expected position on either strand
+   */
+  RF_NO_MATCH,
+  /**
+   * Flanks found on both strands
+   */
+  RF_AMBIGUOUS,
+  /**
+   * Insert contains characters other than A, C, G or T
+   */
+  RF_NON_DNA_INSERT,
+  /**
+   * Lowest quality of the insert below `qual_peak`
+   */
+  RF_PEAK_QUAL_REJECTED,
+  /**
+   * Average quality of the insert below `qual_mean`
+   */
+  RF_MEAN_QUAL_REJECTED,
+  /**
+   * A required pointer was NULL or the `RfMatch` belongs to a filter with a different insert length
+   */
+  RF_INVALID_ARGUMENT,
+} RfStatus;
+
+/**
+ * Opaque filter created by `rf_filter_from_json`
+ */
+typedef struct RfFilter RfFilter;
+
+/**
+ * Extracted insert, allocate with `rf_match_new` and reuse it for every read
+ */
+typedef struct RfMatch {
+  /**
+   * Insert in the orientation of the pattern, NUL-terminated
+   */
+  char *insert;
+  /**
+   * FASTQ encoded quality of the insert in the same orientation, NUL-terminated
+   */
+  char *quality;
+  /**
+   * Length of `insert` and `quality` without the terminating NUL
+   */
+  size_t length;
+  /**
+   * Whether the insert was found on the reverse complement strand
+   */
+  bool reverse_strand;
+  /**
+   * Index from the (reverse complement) start of the read to the insert
+   */
+  uint32_t start_pos;
+} RfMatch;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates a filter from the content of a read_filter JSON config
+ *
+ * Returns NULL if the config is invalid, see `rf_last_error` for the reason.
+ *
+ * # Safety
+ * `json` has to be a valid NUL-terminated string.
+ */
+struct RfFilter *rf_filter_from_json(const char *json);
+
+/**
+ * Releases a filter, NULL is ignored
+ *
+ * # Safety
+ * `filter` has to originate from `rf_filter_from_json` and may not be used afterwards.
+ */
+void rf_filter_free(struct RfFilter *filter);
+
+/**
+ * Length of the inserts extracted by `filter`
+ *
+ * # Safety
+ * `filter` has to be a valid pointer from `rf_filter_from_json`.
+ */
+size_t rf_insert_length(const struct RfFilter *filter);
+
+/**
+ * Allocates a match able to hold the inserts of `filter`, NULL if `filter` is NULL
+ *
+ * # Safety
+ * `filter` has to be a valid pointer from `rf_filter_from_json`.
+ */
+struct RfMatch *rf_match_new(const struct RfFilter *filter);
+
+/**
+ * Releases a match, NULL is ignored
+ *
+ * # Safety
+ * `mat` has to originate from `rf_match_new` and its fields must not have been reassigned.
+ */
+void rf_match_free(struct RfMatch *mat);
+
+/**
+ * Extracts the insert of a single read
+ *
+ * On `RfAccepted` and on the quality based rejections `out` holds the insert, otherwise it is left untouched.
+ *
+ * # Safety
+ * `seq` and `qual` have to point to `read_len` readable bytes,
+ * `out` has to be allocated by `rf_match_new` with the same `filter`.
+ */
+enum RfStatus rf_filter_read(const struct RfFilter *filter,
+                             const char *seq,
+                             const char *qual,
+                             size_t read_len,
+                             struct RfMatch *out);
+
+/**
+ * Message of the last failed `rf_filter_from_json` on this thread, NULL if there was none
+ *
+ * The string is owned by the library and valid until the next failing call on the same thread.
+ */
+const char *rf_last_error(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* READ_FILTER_H */
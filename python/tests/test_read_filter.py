@@ -0,0 +1,96 @@
+from pathlib import Path
+
+import numpy as np
+import pytest
+
+import read_filter
+
+DATA = Path(__file__).resolve().parents[2] / "tests" / "data"
+FASTQ = str(DATA / "sample.fastq")
+CONFIG = str(DATA / "sample_config.json")
+
+EXPECTED_COUNTS = {
+    "ACGTACGTACGTACGTACGTA": 3,
+    "TTTTTCCCCCGGGGGAAAAAC": 2,
+    "GATTACAGATTACAGATTACA": 1,
+}
+
+
+@pytest.fixture
+def rf():
+    return read_filter.ReadFilter.from_json(CONFIG)
+
+
+def test_count(rf):
+    counts, stats = rf.count(FASTQ)
+    assert counts == EXPECTED_COUNTS
+    assert stats["raw_total_reads"] == 8
+    assert stats["matching_reads"] == 7
+    assert stats["peak_qual_rejected_reads"] == 1
+
+
+def test_constructor_matches_config(rf):
+    manual = read_filter.ReadFilter(
+        "AGAGAGGC", "GCCCAGGC", 21, expect_begin=36, tolerance=4, qual_peak=20
+    )
+    assert manual.count(FASTQ) == rf.count(FASTQ)
+
+
+def test_invalid_flank():
+    with pytest.raises(ValueError):
+        read_filter.ReadFilter("AGAGNGGC", "GCCCAGGC", 21, 36, 4)
+
+
+def test_match_both_strands(rf):
+    with open(FASTQ) as fq:
+        lines = fq.read().splitlines()
+    # read_1 carries the insert on the reverse strand
+    seq, qual = lines[5], lines[7]
+    fwd, rev = read_filter.match_both_strands(rf, seq, qual)
+    assert fwd is None
+    insert, insert_qual, reverse_strand, _start = rev
+    assert insert == "ACGTACGTACGTACGTACGTA"
+    assert insert_qual == "I" * 21
+    assert reverse_strand
+    assert rf.match_read(seq, qual) == (fwd, rev)
+
+
+def test_process_reports(rf):
+    res = rf.process(FASTQ, read_report=True, qc_report=True)
+    assert res["counts"] == EXPECTED_COUNTS
+
+    report = res["read_report"]
+    assert len(report["read"]) == 6
+    assert report["qual"].shape == (6, 21)
+    assert report["reversed"].dtype == np.bool_
+    assert np.all(report["qual"] == 40)
+
+    qc = res["qc_report"]
+    assert qc["reads"].sum() == 6
+    assert qc["qual"].shape == (len(qc["reads"]), 21)
+    np.testing.assert_allclose(qc["qual"], 40.0)
+
+
+def test_process_without_reports(rf):
+    res = rf.process(FASTQ)
+    assert set(res) == {"counts", "stats"}
+
+
+def test_fasta_refuses_quality_features(rf, tmp_path):
+    with open(FASTQ) as fq:
+        lines = fq.read().splitlines()
+    fasta = tmp_path / "sample.fasta"
+    fasta.write_text(
+        "".join(f">{lines[i][1:]}\n{lines[i + 1]}\n" for i in range(0, len(lines), 4))
+    )
+    # The config requires qual_peak
+    with pytest.raises(ValueError, match="qual_peak"):
+        rf.count(str(fasta))
+
+    unfiltered = read_filter.ReadFilter(
+        "AGAGAGGC", "GCCCAGGC", 21, expect_begin=36, tolerance=4
+    )
+    counts, stats = unfiltered.count(str(fasta))
+    assert stats["raw_total_reads"] == 8
+    with pytest.raises(ValueError, match="qc_report"):
+        unfiltered.process(str(fasta), qc_report=True)
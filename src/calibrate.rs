@@ -0,0 +1,135 @@
+//! Suggesting the positional window from the observed flank positions
+use std::collections::BTreeMap;
+use std::io;
+
+use bio::io::fastq;
+
+use crate::config::FilterParams;
+use crate::matching::{match_seq_both_strands, PrecomputedPatterns};
+
+/// First position after skipping `skip` hits
+fn first_after<'a, I>(mut positions: I, skip: u32) -> Option<u32>
+where
+    I: Iterator<Item = (&'a u32, &'a (u32, u32))>,
+{
+    let mut seen = 0;
+    positions
+        .find(|(_, (fwd, rev))| {
+            seen += fwd + rev;
+            seen > skip
+        })
+        .map(|(&pos, _)| pos)
+}
+
+/// Starts of the left flank of full pattern matches anywhere in the reads
+///
+/// Positions are given in the coordinates of `expect_begin`, counted from the read end for the reverse strand.
+pub struct PositionHistogram {
+    pats: PrecomputedPatterns,
+    pub scanned_reads: u32,
+    pub matching_reads: u32,
+    /// Forward and reverse strand hits per position
+    positions: BTreeMap<u32, (u32, u32)>,
+}
+
+impl PositionHistogram {
+    /// Searches for the flanks of `filter` without its positional window
+    pub fn new(filter: &FilterParams) -> Self {
+        let unconstrained = FilterParams {
+            position: None,
+            ..filter.clone()
+        };
+        PositionHistogram {
+            pats: PrecomputedPatterns::new(&unconstrained),
+            scanned_reads: 0,
+            matching_reads: 0,
+            positions: BTreeMap::new(),
+        }
+    }
+
+    pub fn append(&mut self, rec: &fastq::Record) {
+        self.scanned_reads += 1;
+        let (fwd, rev) = match_seq_both_strands(rec.seq(), rec.qual(), &self.pats);
+        if fwd.is_none() && rev.is_none() {
+            return;
+        }
+        self.matching_reads += 1;
+        let start_len = self.pats.start_len as u32;
+        if let Some(mat) = fwd {
+            self.positions
+                .entry(mat.start_pos() - start_len)
+                .or_default()
+                .0 += 1;
+        }
+        if let Some(mat) = rev {
+            self.positions
+                .entry(mat.start_pos() - start_len)
+                .or_default()
+                .1 += 1;
+        }
+    }
+
+    /// `expect_begin` and `tolerance` of the narrowest symmetric window keeping `coverage` of the hits
+    ///
+    /// The hits beyond the window are split evenly between both tails. `None` without any hit.
+    pub fn suggest(&self, coverage: f64) -> Option<(u32, u32)> {
+        let hits: u32 = self.positions.values().map(|(f, r)| f + r).sum();
+        if hits == 0 {
+            return None;
+        }
+        // Tolerates the rounding error of e.g. 0.8 * 10
+        let kept = ((coverage * hits as f64) - 1e-9).ceil().max(0.) as u32;
+        let tail = hits.saturating_sub(kept) / 2;
+        let lower = first_after(self.positions.iter(), tail)?;
+        let upper = first_after(self.positions.iter().rev(), tail)?;
+        let expect_begin = lower + (upper - lower).div_ceil(2);
+        Some((
+            expect_begin,
+            (expect_begin - lower).max(upper - expect_begin),
+        ))
+    }
+
+    /// Writes the scan summary, the suggestion and the histogram as `flank_start\tfwd_reads\trev_reads`
+    pub fn write_to_buf<T: io::Write>(&self, buf: &mut T, coverage: f64) -> io::Result<()> {
+        writeln!(buf, "# scanned_reads: {}", self.scanned_reads)?;
+        writeln!(buf, "# matching_reads: {}", self.matching_reads)?;
+        if let Some((expect_begin, tolerance)) = self.suggest(coverage) {
+            writeln!(buf, "# coverage: {}", coverage)?;
+            writeln!(buf, "# suggested_expect_begin: {}", expect_begin)?;
+            writeln!(buf, "# suggested_tolerance: {}", tolerance)?;
+        }
+        writeln!(buf, "flank_start\tfwd_reads\trev_reads")?;
+        for (pos, (fwd, rev)) in &self.positions {
+            writeln!(buf, "{}\t{}\t{}", pos, fwd, rev)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FilterBuilder;
+
+    #[test]
+    fn test_suggest_window() {
+        let filter = FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+            .window(0, 0)
+            .build()
+            .unwrap();
+        let mut hist = PositionHistogram::new(&filter);
+        let qual = vec![b'I'; 45];
+        for offset in [3, 4, 4, 5, 5, 5, 6, 6, 7, 20] {
+            let seq = format!(
+                "{}AGGGCCAGTTTTTGCCCAGGC{}",
+                "A".repeat(offset),
+                "A".repeat(24 - offset)
+            );
+            hist.append(&fastq::Record::with_attrs("r", None, seq.as_bytes(), &qual));
+        }
+        assert_eq!(hist.matching_reads, 10);
+        assert_eq!(hist.suggest(1.), Some((12, 9)));
+        // The outlier at 20 is dropped
+        assert_eq!(hist.suggest(0.8), Some((6, 2)));
+    }
+}
@@ -0,0 +1,215 @@
+//! C ABI around the insert extraction of single reads
+//!
+//! The matching header is `include/read_filter.h`, regenerate it with `cbindgen --output include/read_filter.h`.
+//! A filter is immutable after construction and may be shared between threads,
+//! every thread needs its own `RfMatch` to receive the results.
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+
+use crate::config::{FilterConf, FilterParams};
+use crate::matching::{check_quality, filter_read, PrecomputedPatterns, Rejection};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(msg: String) {
+    let msg = CString::new(msg).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg));
+}
+
+/// Outcome of `rf_filter_read`
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RfStatus {
+    /// Insert extracted and written to the `RfMatch`
+    RfAccepted = 0,
+    /// Flanks not found at the expected position on either strand
+    RfNoMatch,
+    /// Flanks found on both strands
+    RfAmbiguous,
+    /// Insert contains characters other than A, C, G or T
+    RfNonDnaInsert,
+    /// Lowest quality of the insert below `qual_peak`
+    RfPeakQualRejected,
+    /// Average quality of the insert below `qual_mean`
+    RfMeanQualRejected,
+    /// A required pointer was NULL or the `RfMatch` belongs to a filter with a different insert length
+    RfInvalidArgument,
+}
+
+impl From<Rejection> for RfStatus {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::NoMatch => RfStatus::RfNoMatch,
+            Rejection::Ambiguous => RfStatus::RfAmbiguous,
+            Rejection::NonDnaInsert => RfStatus::RfNonDnaInsert,
+            Rejection::PeakQual => RfStatus::RfPeakQualRejected,
+            Rejection::MeanQual => RfStatus::RfMeanQualRejected,
+        }
+    }
+}
+
+/// Opaque filter created by `rf_filter_from_json`
+pub struct RfFilter {
+    pats: PrecomputedPatterns,
+    min_peak_qual: Option<u8>,
+    min_mean_qual: Option<u8>,
+}
+
+/// Extracted insert, allocate with `rf_match_new` and reuse it for every read
+#[repr(C)]
+pub struct RfMatch {
+    /// Insert in the orientation of the pattern, NUL-terminated
+    pub insert: *mut c_char,
+    /// FASTQ encoded quality of the insert in the same orientation, NUL-terminated
+    pub quality: *mut c_char,
+    /// Length of `insert` and `quality` without the terminating NUL
+    pub length: usize,
+    /// Whether the insert was found on the reverse complement strand
+    pub reverse_strand: bool,
+    /// Index from the (reverse complement) start of the read to the insert
+    pub start_pos: u32,
+}
+
+/// Creates a filter from the content of a read_filter JSON config
+///
+/// Returns NULL if the config is invalid, see `rf_last_error` for the reason.
+///
+/// # Safety
+/// `json` has to be a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn rf_filter_from_json(json: *const c_char) -> *mut RfFilter {
+    if json.is_null() {
+        set_last_error("Config string is NULL".to_string());
+        return ptr::null_mut();
+    }
+    let json = match CStr::from_ptr(json).to_str() {
+        Ok(json) => json,
+        Err(e) => {
+            set_last_error(format!("Config is not valid UTF-8: {}", e));
+            return ptr::null_mut();
+        }
+    };
+    let params = serde_json::from_str::<FilterConf>(json)
+        .map_err(|e| e.into())
+        .and_then(FilterParams::from_conf);
+    match params {
+        Ok(params) => Box::into_raw(Box::new(RfFilter {
+            pats: PrecomputedPatterns::new(&params),
+            min_peak_qual: params.min_peak_qual,
+            min_mean_qual: params.min_mean_qual,
+        })),
+        Err(e) => {
+            set_last_error(format!("Invalid configuration: {}", e));
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Releases a filter, NULL is ignored
+///
+/// # Safety
+/// `filter` has to originate from `rf_filter_from_json` and may not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn rf_filter_free(filter: *mut RfFilter) {
+    if !filter.is_null() {
+        drop(Box::from_raw(filter));
+    }
+}
+
+/// Length of the inserts extracted by `filter`
+///
+/// # Safety
+/// `filter` has to be a valid pointer from `rf_filter_from_json`.
+#[no_mangle]
+pub unsafe extern "C" fn rf_insert_length(filter: *const RfFilter) -> usize {
+    filter.as_ref().map_or(0, |f| f.pats.content_len)
+}
+
+/// Allocates a match able to hold the inserts of `filter`, NULL if `filter` is NULL
+///
+/// # Safety
+/// `filter` has to be a valid pointer from `rf_filter_from_json`.
+#[no_mangle]
+pub unsafe extern "C" fn rf_match_new(filter: *const RfFilter) -> *mut RfMatch {
+    let filter = match filter.as_ref() {
+        Some(f) => f,
+        None => return ptr::null_mut(),
+    };
+    let alloc = |len: usize| CString::new(vec![b'N'; len]).unwrap().into_raw();
+    let length = filter.pats.content_len;
+    Box::into_raw(Box::new(RfMatch {
+        insert: alloc(length),
+        quality: alloc(length),
+        length,
+        reverse_strand: false,
+        start_pos: 0,
+    }))
+}
+
+/// Releases a match, NULL is ignored
+///
+/// # Safety
+/// `mat` has to originate from `rf_match_new` and its fields must not have been reassigned.
+#[no_mangle]
+pub unsafe extern "C" fn rf_match_free(mat: *mut RfMatch) {
+    if !mat.is_null() {
+        let mat = Box::from_raw(mat);
+        drop(CString::from_raw(mat.insert));
+        drop(CString::from_raw(mat.quality));
+    }
+}
+
+/// Extracts the insert of a single read
+///
+/// On `RfAccepted` and on the quality based rejections `out` holds the insert, otherwise it is left untouched.
+///
+/// # Safety
+/// `seq` and `qual` have to point to `read_len` readable bytes,
+/// `out` has to be allocated by `rf_match_new` with the same `filter`.
+#[no_mangle]
+pub unsafe extern "C" fn rf_filter_read(
+    filter: *const RfFilter,
+    seq: *const c_char,
+    qual: *const c_char,
+    read_len: usize,
+    out: *mut RfMatch,
+) -> RfStatus {
+    let (filter, out) = match (filter.as_ref(), out.as_mut()) {
+        (Some(f), Some(o)) if !seq.is_null() && !qual.is_null() => (f, o),
+        _ => return RfStatus::RfInvalidArgument,
+    };
+    let seq = std::slice::from_raw_parts(seq as *const u8, read_len);
+    let qual = std::slice::from_raw_parts(qual as *const u8, read_len);
+    if out.length != filter.pats.content_len {
+        return RfStatus::RfInvalidArgument;
+    }
+    // The quality thresholds are checked separately to report the insert of rejected reads
+    let candidate = match filter_read(seq, qual, &filter.pats, None, None) {
+        Ok(candidate) => candidate,
+        Err(rejection) => return rejection.into(),
+    };
+    let status = match check_quality(&candidate, filter.min_peak_qual, filter.min_mean_qual) {
+        Ok(()) => RfStatus::RfAccepted,
+        Err(rejection) => rejection.into(),
+    };
+    let mat = candidate.materialize();
+    let insert = std::slice::from_raw_parts_mut(out.insert as *mut u8, out.length);
+    let quality = std::slice::from_raw_parts_mut(out.quality as *mut u8, out.length);
+    insert.copy_from_slice(&mat.seq);
+    quality.copy_from_slice(&mat.quality);
+    out.reverse_strand = mat.reverse_strand;
+    out.start_pos = mat.start_pos;
+    status
+}
+
+/// Message of the last failed `rf_filter_from_json` on this thread, NULL if there was none
+///
+/// The string is owned by the library and valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn rf_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |msg| msg.as_ptr()))
+}
@@ -0,0 +1,253 @@
+//! Parquet and Arrow IPC versions of the counts table and the read report
+//!
+//! The `#` header lines of the tab-separated tables are stored as file-level key-value metadata,
+//! JSON encoded unless they are plain strings.
+use std::io;
+use std::sync::Arc;
+
+use arrow_array::builder::{
+    BooleanBuilder, Float32Builder, StringBuilder, UInt32Builder, UInt8Builder,
+};
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use counter::Counter;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::metadata::KeyValue;
+use parquet::file::properties::WriterProperties;
+use serde_json::{Map, Value};
+
+use crate::match_type::SearchMatch;
+use crate::naming::TableFormat;
+use crate::output::{reads_per_million, sorted_counts, CountsFormat};
+use crate::sink::MatchSink;
+
+/// Rows of the read report collected before they are written as one batch
+const BATCH_ROWS: usize = 8192;
+
+fn to_io_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::other(e)
+}
+
+/// Writer of record batches in one of the columnar `TableFormat`s
+enum TableWriter<W: io::Write + Send> {
+    Parquet(ArrowWriter<W>),
+    Arrow(FileWriter<W>),
+}
+
+impl<W: io::Write + Send> TableWriter<W> {
+    fn new(buf: W, format: TableFormat, schema: SchemaRef) -> io::Result<Self> {
+        match format {
+            TableFormat::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(Compression::SNAPPY)
+                    .build();
+                ArrowWriter::try_new(buf, schema, Some(props))
+                    .map(TableWriter::Parquet)
+                    .map_err(to_io_error)
+            }
+            TableFormat::Arrow => FileWriter::try_new(buf, &schema)
+                .map(TableWriter::Arrow)
+                .map_err(to_io_error),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} is not a columnar format", format),
+            )),
+        }
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> io::Result<()> {
+        match self {
+            TableWriter::Parquet(wtr) => wtr.write(batch).map_err(to_io_error),
+            TableWriter::Arrow(wtr) => wtr.write(batch).map_err(to_io_error),
+        }
+    }
+
+    /// Stores the `metadata` in the footer and flushes the output
+    fn finish(&mut self, metadata: &Map<String, Value>) -> io::Result<()> {
+        for (key, value) in metadata {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            match self {
+                TableWriter::Parquet(wtr) => {
+                    wtr.append_key_value_metadata(KeyValue::new(key.clone(), value))
+                }
+                TableWriter::Arrow(wtr) => wtr.write_metadata(key.clone(), value),
+            }
+        }
+        match self {
+            TableWriter::Parquet(wtr) => {
+                wtr.finish().map_err(to_io_error)?;
+                wtr.inner_mut().flush()
+            }
+            TableWriter::Arrow(wtr) => {
+                wtr.finish().map_err(to_io_error)?;
+                wtr.get_mut().flush()
+            }
+        }
+    }
+}
+
+/// Counts table with the columns `seq`, `reads` and optionally `rpm`, rows as by `sorted_counts`
+pub fn write_counts<W: io::Write + Send>(
+    buf: W,
+    table_format: TableFormat,
+    counter: &Counter<Vec<u8>>,
+    format: &CountsFormat,
+    metadata: &Map<String, Value>,
+) -> io::Result<()> {
+    let total: usize = counter.values().sum();
+    let rows = sorted_counts(counter, format);
+    let mut fields = vec![
+        Field::new("seq", DataType::Utf8, false),
+        Field::new("reads", DataType::UInt64, false),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|(seq, _)| String::from_utf8_lossy(seq)),
+        )),
+        Arc::new(UInt64Array::from_iter_values(
+            rows.iter().map(|(_, count)| *count as u64),
+        )),
+    ];
+    if format.rpm {
+        fields.push(Field::new("rpm", DataType::Float64, false));
+        columns.push(Arc::new(Float64Array::from_iter_values(
+            rows.iter()
+                .map(|(_, count)| reads_per_million(*count, total)),
+        )));
+    }
+    let schema = Arc::new(Schema::new(fields));
+    let batch = RecordBatch::try_new(schema.clone(), columns).map_err(to_io_error)?;
+    let mut wtr = TableWriter::new(buf, table_format, schema)?;
+    wtr.write(&batch)?;
+    wtr.finish(metadata)
+}
+
+/// Writes the per read report with the columns of the tab-separated one in batches
+///
+/// The metadata is only known after the run, set it with `set_metadata` before finishing.
+pub struct ColumnarReadReportSink<W: io::Write + Send> {
+    wtr: TableWriter<W>,
+    schema: SchemaRef,
+    read: StringBuilder,
+    dist_start: UInt32Builder,
+    reversed: BooleanBuilder,
+    peak_qual: UInt8Builder,
+    mean_qual: Float32Builder,
+    qual: Vec<UInt8Builder>,
+    rows: usize,
+    metadata: Map<String, Value>,
+}
+
+impl<W: io::Write + Send> ColumnarReadReportSink<W> {
+    /// Columns for inserts of `seq_len`
+    pub fn new(buf: W, format: TableFormat, seq_len: usize) -> io::Result<Self> {
+        let mut fields = vec![
+            Field::new("read", DataType::Utf8, false),
+            Field::new("dist_start", DataType::UInt32, false),
+            Field::new("reversed", DataType::Boolean, false),
+            Field::new("peak_qual", DataType::UInt8, false),
+            Field::new("mean_qual", DataType::Float32, false),
+        ];
+        fields.extend(
+            (0..seq_len).map(|i| Field::new(format!("qual_pos_{}", i), DataType::UInt8, false)),
+        );
+        let schema = Arc::new(Schema::new(fields));
+        Ok(ColumnarReadReportSink {
+            wtr: TableWriter::new(buf, format, schema.clone())?,
+            schema,
+            read: StringBuilder::new(),
+            dist_start: UInt32Builder::new(),
+            reversed: BooleanBuilder::new(),
+            peak_qual: UInt8Builder::new(),
+            mean_qual: Float32Builder::new(),
+            qual: (0..seq_len).map(|_| UInt8Builder::new()).collect(),
+            rows: 0,
+            metadata: Map::new(),
+        })
+    }
+
+    /// Key-value metadata stored when finishing, e.g. from `output::table_metadata`
+    pub fn set_metadata(&mut self, metadata: Map<String, Value>) {
+        self.metadata = metadata;
+    }
+
+    fn write_batch(&mut self) -> io::Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(self.read.finish()),
+            Arc::new(self.dist_start.finish()),
+            Arc::new(self.reversed.finish()),
+            Arc::new(self.peak_qual.finish()),
+            Arc::new(self.mean_qual.finish()),
+        ];
+        columns.extend(
+            self.qual
+                .iter_mut()
+                .map(|builder| Arc::new(builder.finish()) as ArrayRef),
+        );
+        let batch = RecordBatch::try_new(self.schema.clone(), columns).map_err(to_io_error)?;
+        self.rows = 0;
+        self.wtr.write(&batch)
+    }
+}
+
+impl<W: io::Write + Send> MatchSink for ColumnarReadReportSink<W> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        self.read.append_value(String::from_utf8_lossy(&m.seq));
+        self.dist_start.append_value(m.start_pos);
+        self.reversed.append_value(m.reverse_strand);
+        self.peak_qual.append_value(m.peak_qual());
+        self.mean_qual.append_value(m.accurate_mean_qual());
+        for (builder, q) in self.qual.iter_mut().zip(&m.quality) {
+            builder.append_value(q - 33);
+        }
+        self.rows += 1;
+        if self.rows >= BATCH_ROWS {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_batch()?;
+        self.wtr.finish(&self.metadata)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use arrow_ipc::reader::FileReader;
+
+    #[test]
+    fn test_counts_metadata() {
+        let mut counter = Counter::new();
+        counter.insert(b"ACG".to_vec(), 3);
+        counter.insert(b"TTT".to_vec(), 1);
+        let mut metadata = Map::new();
+        metadata.insert("filter".into(), Value::from("^.*ACG$"));
+        metadata.insert("inputs".into(), serde_json::json!([{"input": "a.fq"}]));
+        let mut buf = Vec::new();
+        write_counts(
+            &mut buf,
+            TableFormat::Arrow,
+            &counter,
+            &CountsFormat::default(),
+            &metadata,
+        )
+        .unwrap();
+        let mut reader = FileReader::try_new(io::Cursor::new(buf), None).unwrap();
+        let stored = reader.custom_metadata();
+        assert_eq!(stored["filter"], "^.*ACG$");
+        assert_eq!(stored["inputs"], r#"[{"input":"a.fq"}]"#);
+        let batch = reader.next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(batch.schema().field(0).name(), "seq");
+    }
+}
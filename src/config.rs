@@ -1,7 +1,21 @@
 //! Utilities for loading user config from the command line and json config files
+use crate::diversity::DesignTemplate;
+use crate::enrich::EnrichParams;
 use crate::handling::{GracefulOption, GracefulResult};
-use serde::Deserialize;
-use std::{error::Error, fs::File};
+use crate::matching::is_dna_char;
+use crate::naming::{
+    check_sample_name, sample_name_from_path, Compression, CompressionChoice, OutputTemplate,
+    TableFormat,
+};
+use crate::output::CountsFormat;
+use crate::stat::QualBins;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::{error::Error, fs::File, path::Path};
+
+/// Upper limit of the flank length given by the bit-parallel pattern matching
+const MAX_FLANK_LEN: usize = 64;
 
 /// JSON config for read_filter
 /// ## Example
@@ -11,9 +25,13 @@ use std::{error::Error, fs::File};
 /// ....(approx. 36 nt)...AGAGAGGC...(21 nt)...GCCCAGGC...
 /// ```
 /// We configure with `tolerance` how sensitive we are to position variation.
+/// Without `expect_begin` and `tolerance` the flanks are searched in the entire read,
+/// e.g. when only the true amplicon can contain both flanks.
 ///
 /// To filter by quality we have two optional parameters `qual_peak` and `qual_mean` which define the minimal required PHRED score either for a single position or on average over the sequence of interest
 ///
+/// The optional `design` is an IUPAC template of the designed inserts (e.g. `NNK` codons) used to report the library coverage.
+///
 /// ``` json
 /// {
 ///     "left_flank": "AGAGAGGC",
@@ -25,15 +43,37 @@ use std::{error::Error, fs::File};
 ///     "qual_mean": 30
 /// }
 /// ```
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct FilterConf {
     pub left_flank: String,
     pub right_flank: String,
     pub content_length: u32,
-    pub expect_begin: u32,
-    pub tolerance: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expect_begin: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tolerance: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub qual_peak: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub qual_mean: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub design: Option<String>,
+}
+
+/// Config file reproducing the `FilterParams`, e.g. to store a calibrated window
+impl From<&FilterParams> for FilterConf {
+    fn from(params: &FilterParams) -> Self {
+        FilterConf {
+            left_flank: params.left_flank.clone(),
+            right_flank: params.right_flank.clone(),
+            content_length: params.insert_length,
+            expect_begin: params.position.map(|(start, _)| start),
+            tolerance: params.position.map(|(_, tolerance)| tolerance),
+            qual_peak: params.min_peak_qual,
+            qual_mean: params.min_mean_qual,
+            design: params.design.clone(),
+        }
+    }
 }
 
 pub fn load_json_config<P: AsRef<std::path::Path>>(
@@ -62,73 +102,919 @@ mod test {
         let result: FilterConf = serde_json::from_str(js_str).unwrap();
         assert_eq!(result.qual_mean.unwrap(), 30u8);
     }
+
+    #[test]
+    fn test_config_formats_and_overrides() {
+        let toml = "left_flank = \"AGGGCCAG\"\nright_flank = \"GCCCAGGC\"\ncontent_length = 27\nexpect_begin = 36\ntolerance = 8\n";
+        let yaml = "left_flank: AGGGCCAG\nright_flank: GCCCAGGC\ncontent_length: 27\nexpect_begin: 36\ntolerance: 8\n";
+        for (text, format) in [(toml, ConfigFormat::Toml), (yaml, ConfigFormat::Yaml)] {
+            let conf = parse_config(
+                text,
+                format,
+                &["qual_mean=25", "design=NNKNNKNNKNNKNNKNNKNNKNNKNNK"],
+            )
+            .unwrap();
+            let params = FilterParams::from_conf(conf).unwrap();
+            assert_eq!(params.window(), Some((28, 44)));
+            assert_eq!(params.min_mean_qual, Some(25));
+            assert_eq!(params.design.unwrap().len(), 27);
+        }
+        let conf = parse_config(
+            toml,
+            ConfigFormat::Toml,
+            &["expect_begin=null", "tolerance=null"],
+        )
+        .unwrap();
+        assert_eq!(conf.expect_begin, None);
+        assert_eq!(
+            ConfigFormat::from_path("sweep/config.yml"),
+            ConfigFormat::Yaml
+        );
+        assert!(parse_config(toml, ConfigFormat::Toml, &["qual_min=25"]).is_err());
+        assert!(parse_config(toml, ConfigFormat::Toml, &["qual_mean"]).is_err());
+    }
+
+    #[test]
+    fn test_optional_window() {
+        let conf = |window: &str| {
+            let js = format!(
+                r#"{{"left_flank": "AGGGCCAG", "right_flank": "GCCCAGGC", "content_length": 27{}}}"#,
+                window
+            );
+            FilterParams::from_conf(serde_json::from_str(&js).unwrap())
+        };
+        assert_eq!(conf("").unwrap().window(), None);
+        assert_eq!(
+            conf(r#", "expect_begin": 36, "tolerance": 8"#)
+                .unwrap()
+                .window(),
+            Some((28, 44))
+        );
+        assert!(conf(r#", "expect_begin": 36"#).is_err());
+    }
+
+    #[test]
+    fn test_builder_rejects_illegal_flanks() {
+        assert!(FilterBuilder::new("AGGGCCAG", "GCCNAGGC", 27)
+            .window(36, 8)
+            .build()
+            .is_err());
+        assert!(
+            FilterBuilder::new("A".repeat(65), "GCCCAGGC".to_string(), 27)
+                .window(36, 8)
+                .build()
+                .is_err()
+        );
+    }
 }
 
-/// Summarized config used by different parts of the program
-pub struct ProgConfig {
-    pub infile: String,
-    pub outdir: String,
-    pub rr_required: bool,
-    pub qc_required: bool,
+/// Fields of a `FilterConf`, the keys accepted by `--set`
+pub const CONFIG_FIELDS: [&str; 8] = [
+    "left_flank",
+    "right_flank",
+    "content_length",
+    "expect_begin",
+    "tolerance",
+    "qual_peak",
+    "qual_mean",
+    "design",
+];
+
+/// Syntax of a config file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Detected by the extension, JSON unless it is `.toml`, `.yaml` or `.yml`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            Some("yaml") | Some("yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    pub fn to_string(&self, conf: &FilterConf) -> Result<String, Box<dyn Error>> {
+        Ok(match self {
+            ConfigFormat::Json => serde_json::to_string_pretty(conf)? + "\n",
+            ConfigFormat::Toml => toml::to_string(conf)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(conf)?,
+        })
+    }
+}
+
+/// Sets a field of a parsed config from `key=value`
+///
+/// Numbers and `null`, which removes an optional field, are taken as such, anything else as string.
+fn apply_override(fields: &mut Map<String, Value>, arg: &str) -> Result<(), Box<dyn Error>> {
+    let (key, value) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("Invalid override {:?}, use KEY=VALUE", arg))?;
+    if !CONFIG_FIELDS.contains(&key) {
+        return Err(format!(
+            "Unknown config field {:?}, use one of {}",
+            key,
+            CONFIG_FIELDS.join(", ")
+        )
+        .into());
+    }
+    let value = match serde_json::from_str(value) {
+        Ok(value @ (Value::Number(_) | Value::Null)) => value,
+        _ => Value::String(value.to_string()),
+    };
+    fields.insert(key.to_string(), value);
+    Ok(())
+}
+
+/// Parses a config in `format` and applies the `overrides` given as `key=value`
+///
+/// Fields set by the overrides may be missing from the config.
+pub fn parse_config(
+    text: &str,
+    format: ConfigFormat,
+    overrides: &[&str],
+) -> Result<FilterConf, Box<dyn Error>> {
+    let mut value: Value = match format {
+        ConfigFormat::Json => serde_json::from_str(text)?,
+        ConfigFormat::Toml => toml::from_str(text)?,
+        ConfigFormat::Yaml => serde_yaml::from_str(text)?,
+    };
+    let fields = value
+        .as_object_mut()
+        .ok_or("The config has to consist of key-value pairs")?;
+    for arg in overrides {
+        apply_override(fields, arg)?;
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Loads a JSON, TOML or YAML config as detected by its extension, see `parse_config`
+pub fn load_config<P: AsRef<Path>>(
+    path: P,
+    overrides: &[&str],
+) -> Result<FilterConf, Box<dyn Error>> {
+    let text = std::fs::read_to_string(&path)?;
+    parse_config(&text, ConfigFormat::from_path(&path), overrides)
+}
+
+/// Parameters defining which inserts are extracted and accepted by a `ReadFilter`
+///
+/// Construct with `FilterBuilder` or from a parsed `FilterConf` via `FilterParams::from_conf`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterParams {
     pub left_flank: String,
     pub right_flank: String,
     pub insert_length: u32,
-    pub expected_start: u32,
-    pub position_tolerance: u32,
+    /// Expected start of the left flank and its tolerance, `None` searches the entire read
+    pub position: Option<(u32, u32)>,
     pub min_peak_qual: Option<u8>,
     pub min_mean_qual: Option<u8>,
+    /// IUPAC template of the designed inserts, only used for the diversity metrics
+    pub design: Option<String>,
 }
 
-impl ProgConfig {
-    pub fn from_cli() -> Result<ProgConfig, Box<dyn Error>> {
-        // Specify CLI
+impl FilterParams {
+    /// Validates the values of a config file
+    pub fn from_conf(conf: FilterConf) -> Result<FilterParams, Box<dyn Error>> {
+        let mut builder =
+            FilterBuilder::new(conf.left_flank, conf.right_flank, conf.content_length);
+        match (conf.expect_begin, conf.tolerance) {
+            (Some(expected_start), Some(tolerance)) => {
+                builder = builder.window(expected_start, tolerance)
+            }
+            (None, None) => {}
+            _ => return Err("expect_begin and tolerance have to be given together".into()),
+        }
+        if let Some(min) = conf.qual_peak {
+            builder = builder.min_peak_qual(min);
+        }
+        if let Some(min) = conf.qual_mean {
+            builder = builder.min_mean_qual(min);
+        }
+        if let Some(template) = conf.design {
+            builder = builder.design(template);
+        }
+        builder.build()
+    }
+
+    /// Range of accepted positions for the start of the left flank, `None` without positional limits
+    pub fn window(&self) -> Option<(usize, usize)> {
+        self.position.map(|(expected_start, tolerance)| {
+            let expt_begin = expected_start.saturating_sub(tolerance);
+            let expt_end = expected_start.saturating_add(tolerance);
+            (expt_begin as usize, expt_end as usize)
+        })
+    }
+}
+
+/// Programmatic construction of `FilterParams` without a config file or CLI
+///
+/// ```
+/// use read_filter::config::FilterBuilder;
+///
+/// let params = FilterBuilder::new("AGAGAGGC", "GCCCAGGC", 21)
+///     .window(36, 8)
+///     .min_peak_qual(20)
+///     .min_mean_qual(30)
+///     .build()
+///     .unwrap();
+/// assert_eq!(params.window(), Some((28, 44)));
+/// ```
+pub struct FilterBuilder {
+    left_flank: String,
+    right_flank: String,
+    insert_length: u32,
+    window: Option<(u32, u32)>,
+    min_peak_qual: Option<u8>,
+    min_mean_qual: Option<u8>,
+    design: Option<String>,
+}
+
+impl FilterBuilder {
+    pub fn new<S: Into<String>>(left_flank: S, right_flank: S, insert_length: u32) -> Self {
+        FilterBuilder {
+            left_flank: left_flank.into(),
+            right_flank: right_flank.into(),
+            insert_length,
+            window: None,
+            min_peak_qual: None,
+            min_mean_qual: None,
+            design: None,
+        }
+    }
+
+    /// Accept the left flank only if it starts at `expected_start` +/- `tolerance`
+    ///
+    /// Without a window the flanks are searched in the entire read.
+    pub fn window(mut self, expected_start: u32, tolerance: u32) -> Self {
+        self.window = Some((expected_start, tolerance));
+        self
+    }
+
+    /// Minimal PHRED score required at every position of the insert
+    pub fn min_peak_qual(mut self, min: u8) -> Self {
+        self.min_peak_qual = Some(min);
+        self
+    }
+
+    /// Minimal PHRED score required on average over the insert
+    pub fn min_mean_qual(mut self, min: u8) -> Self {
+        self.min_mean_qual = Some(min);
+        self
+    }
+
+    /// IUPAC template of the designed library to report its coverage
+    pub fn design<S: Into<String>>(mut self, template: S) -> Self {
+        self.design = Some(template.into());
+        self
+    }
+
+    /// Checks for illegal values and produces the `FilterParams`
+    pub fn build(self) -> Result<FilterParams, Box<dyn Error>> {
+        for (name, flank) in [
+            ("left_flank", &self.left_flank),
+            ("right_flank", &self.right_flank),
+        ] {
+            if flank.is_empty() || flank.len() > MAX_FLANK_LEN {
+                return Err(format!(
+                    "{} needs to be between 1 and {} nt long",
+                    name, MAX_FLANK_LEN
+                )
+                .into());
+            }
+            if !flank.bytes().all(|c| is_dna_char(&c)) {
+                return Err(format!("{} may only contain A, C, G or T", name).into());
+            }
+        }
+        if self.insert_length == 0 {
+            return Err("content_length needs to be at least 1".into());
+        }
+        if let Some(template) = &self.design {
+            DesignTemplate::new(template, self.insert_length as usize)?;
+        }
+        Ok(FilterParams {
+            left_flank: self.left_flank,
+            right_flank: self.right_flank,
+            insert_length: self.insert_length,
+            position: self.window,
+            min_peak_qual: self.min_peak_qual,
+            min_mean_qual: self.min_mean_qual,
+            design: self.design,
+        })
+    }
+}
+
+/// Summarized config used by different parts of the program
+pub struct ProgConfig {
+    /// Read files processed in order, `-` for stdin
+    pub infiles: Vec<String>,
+    pub outdir: String,
+    /// Given on the command line or derived from a single input file
+    pub sample_name: String,
+    pub output_template: OutputTemplate,
+    pub compression: CompressionChoice,
+    /// Overwrite existing outputs
+    pub force: bool,
+    pub rr_required: bool,
+    pub qc_required: bool,
+    pub fastq_required: bool,
+    pub diversity_required: bool,
+    pub read_qc_required: bool,
+    /// MultiQC custom content files
+    pub multiqc_required: bool,
+    pub html_required: bool,
+    /// JSON file of the provenance
+    pub provenance_required: bool,
+    pub counts_format: CountsFormat,
+    /// Format of the counts table and the read report
+    pub table_format: TableFormat,
+    /// Binned QC report
+    pub qual_bins: Option<QualBins>,
+    pub filter: FilterParams,
+}
+
+/// Single output of a subcommand
+pub struct OutputFile {
+    /// `-` for stdout
+    pub path: String,
+    /// Chosen by `--compress` or inferred from the file name
+    pub compression: Compression,
+    /// Overwrite an existing file
+    pub force: bool,
+}
+
+/// Config of the `count`, `extract` and `qc` subcommands writing a single output
+pub struct StreamConfig {
+    /// Read files processed in order, `-` for stdin
+    pub infiles: Vec<String>,
+    pub output: OutputFile,
+    /// JSON file of the diversity metrics of `count`
+    pub diversity_json: Option<String>,
+    /// Rows of the counts table of `count`
+    pub counts_format: CountsFormat,
+    /// Format of the counts table of `count`
+    pub table_format: TableFormat,
+    /// Binned report of `qc`
+    pub qual_bins: Option<QualBins>,
+    /// Position quality table of `qc`
+    pub position_quality: Option<String>,
+    /// Whole-read QC file of `qc`
+    pub read_qc: Option<String>,
+    /// JSON file of the provenance
+    pub provenance: Option<String>,
+    /// MultiQC custom content of `count` and `qc`
+    pub multiqc: Option<MultiqcOutput>,
+    pub filter: FilterParams,
+}
+
+/// Directory of the MultiQC custom content of a subcommand, named as the legacy outputs
+pub struct MultiqcOutput {
+    pub outdir: String,
+    /// Given on the command line or derived from a single input file
+    pub sample_name: String,
+}
+
+/// Config of the `merge` subcommand
+pub struct MergeConfig {
+    /// Counts tables, one per sample
+    pub tables: Vec<String>,
+    pub output: OutputFile,
+    /// Sparse table with one line per insert and sample instead of the wide matrix
+    pub long: bool,
+    /// Only require equal flanks and insert lengths
+    pub lenient: bool,
+    /// JSON file of the provenance
+    pub provenance: Option<String>,
+}
+
+/// Config of the `enrich` subcommand
+pub struct EnrichConfig {
+    /// Counts tables of the selection rounds, starting with the input
+    pub tables: Vec<String>,
+    pub output: OutputFile,
+    /// Only require equal flanks and insert lengths
+    pub lenient: bool,
+    /// JSON file of the provenance
+    pub provenance: Option<String>,
+    pub params: EnrichParams,
+}
+
+/// Config of the `calibrate` subcommand
+pub struct CalibrateConfig {
+    /// Read files scanned in order, `-` for stdin
+    pub infiles: Vec<String>,
+    pub output: OutputFile,
+    /// Number of reads scanned over all inputs
+    pub reads: usize,
+    /// Fraction of the flank hits the suggested window has to cover
+    pub coverage: f64,
+    /// Config file with the suggested window
+    pub write_config: Option<String>,
+    /// JSON file of the provenance
+    pub provenance: Option<String>,
+    pub filter: FilterParams,
+}
+
+/// Mode of operation selected on the command line
+pub enum Command {
+    /// Original interface writing all requested outputs into a directory
+    Legacy(ProgConfig),
+    /// Counts table with the config and stats header
+    Count(StreamConfig),
+    /// Accepted inserts as FASTQ
+    Extract(StreamConfig),
+    /// Overall QC report
+    Qc(StreamConfig),
+    /// Count matrix of several samples
+    Merge(MergeConfig),
+    /// Enrichment between selection rounds
+    Enrich(EnrichConfig),
+    /// Suggested positional window
+    Calibrate(CalibrateConfig),
+}
+
+/// Output arguments shared by the subcommands
+fn output_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::from_usage("-o, --output [OUTPUT] 'Sets the output file, - for stdout'")
+            .default_value("-"),
+        Arg::from_usage("-z, --compress [FORMAT] 'Compresses the output with gz, zstd or none [default: inferred from the file name]'"),
+        Arg::from_usage("--force 'Overwrite an existing output file'"),
+    ]
+}
+
+/// Arguments shared by the subcommands filtering reads
+fn stream_subcommand<'a, 'b>(name: &'a str, about: &'a str) -> App<'a, 'b> {
+    SubCommand::with_name(name)
+        .about(about)
+        .args(&output_args())
+        .args(&[
+            Arg::with_name("CONFIG")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .required(true)
+                .help("Sets a custom config file"),
+            Arg::from_usage("[INPUT]... 'Sets the input files to use, - for stdin'")
+                .default_value("-"),
+            provenance_arg(),
+            set_arg(),
+        ])
+}
+
+/// Sample name given by `--sample-name` or derived from a single input file
+fn sample_name_from_matches(
+    matches: &ArgMatches,
+    infiles: &[String],
+) -> Result<String, Box<dyn Error>> {
+    let sample_name = match matches.value_of("sample_name") {
+        Some(name) => name.to_string(),
+        None if infiles.len() > 1 || infiles[0] == "-" => {
+            return Err("--sample-name is required for several inputs or stdin".into())
+        }
+        None => sample_name_from_path(&infiles[0])
+            .ok_or_else(|| format!("Cannot derive a sample name from {}", infiles[0]))?,
+    };
+    check_sample_name(&sample_name)?;
+    Ok(sample_name)
+}
+
+/// Arguments of the MultiQC custom content of `count` and `qc`
+fn multiqc_args<'a, 'b>() -> [Arg<'a, 'b>; 2] {
+    [
+        Arg::from_usage("--multiqc [DIR] 'Also writes MultiQC custom content into DIR'"),
+        Arg::with_name("sample_name")
+            .short("s")
+            .long("sample-name")
+            .takes_value(true)
+            .value_name("NAME")
+            .requires("multiqc")
+            .help("Sets the sample of the MultiQC content, required for several inputs or stdin"),
+    ]
+}
+
+/// Provenance sidecar of the subcommands
+fn provenance_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::from_usage("--provenance [FILE] 'Also writes the provenance of the run as JSON'")
+}
+
+/// Loads the `FilterParams` from the file given by `--config` with the `--set` overrides
+fn filter_from_matches(matches: &ArgMatches) -> FilterParams {
+    let config_file = matches
+        .value_of("CONFIG")
+        .unwrap_graceful("Missing config file");
+    let overrides: Vec<&str> = matches.values_of("set").into_iter().flatten().collect();
+    let config =
+        load_config(config_file, &overrides).unwrap_formatful("While parsing configuration");
+
+    // TODO: Add checks to block useless inputs/configs
+    // If read length would be known, exptected start + total pattern length beyond read_length
+
+    FilterParams::from_conf(config).unwrap_formatful("Invalid configuration")
+}
+
+/// `--set` of the legacy interface and the subcommands filtering reads
+fn set_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("set")
+        .long("set")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("KEY=VALUE")
+        .help("Overrides a field of the config, e.g. qual_mean=25 or expect_begin=null")
+}
+
+/// Options of the counts table shared by the legacy interface and `count`
+fn counts_format_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::from_usage(
+            "--min-count [N] 'Leaves out inserts with fewer reads from the counts table'",
+        ),
+        Arg::from_usage("--top [N] 'Limits the counts table to the N most abundant inserts'"),
+        Arg::from_usage("--rpm 'Adds the reads per million to the counts table'"),
+    ]
+}
+
+fn counts_format_from_matches(matches: &ArgMatches) -> Result<CountsFormat, Box<dyn Error>> {
+    let min_count = match matches.value_of("min-count") {
+        Some(n) => n
+            .parse()
+            .map_err(|e| format!("Invalid --min-count: {}", e))?,
+        None => 0,
+    };
+    let top = match matches.value_of("top") {
+        Some(n) => Some(n.parse().map_err(|e| format!("Invalid --top: {}", e))?),
+        None => None,
+    };
+    Ok(CountsFormat {
+        min_count,
+        top,
+        rpm: matches.is_present("rpm"),
+    })
+}
+
+/// `--format` of the legacy interface and `count`
+fn table_format_arg<'a, 'b>(help: &'a str) -> Arg<'a, 'b> {
+    Arg::with_name("format")
+        .long("format")
+        .takes_value(true)
+        .value_name("FORMAT")
+        .possible_values(&["tsv", "json", "parquet", "arrow"])
+        .default_value("tsv")
+        .help(help)
+}
+
+fn table_format_from_matches(matches: &ArgMatches) -> Result<TableFormat, Box<dyn Error>> {
+    match matches.value_of("format") {
+        Some(format) => format.parse(),
+        None => Ok(TableFormat::Tsv),
+    }
+}
+
+/// Options of the binned QC report shared by the legacy interface and `qc`
+fn qual_bins_args<'a, 'b>() -> [Arg<'a, 'b>; 3] {
+    [
+        Arg::from_usage("--qc-bins 'Groups the QC report into bins of start position and quality'"),
+        Arg::from_usage("--position-bins [EDGES] 'Sets the lower edges of the start position bins, implies --qc-bins [default: 0,10,...,300]'"),
+        Arg::from_usage("--qual-bins [EDGES] 'Sets the lower edges of the quality bins, implies --qc-bins [default: 0,10,20,30]'"),
+    ]
+}
+
+/// `None` keeps the QC report with one row per start position and quality
+fn qual_bins_from_matches(matches: &ArgMatches) -> Result<Option<QualBins>, Box<dyn Error>> {
+    let position = matches.value_of("position-bins");
+    let quality = matches.value_of("qual-bins");
+    if !matches.is_present("qc-bins") && position.is_none() && quality.is_none() {
+        return Ok(None);
+    }
+    let mut bins = QualBins::default();
+    if let Some(edges) = position {
+        bins.position = QualBins::parse_edges(edges)?;
+    }
+    if let Some(edges) = quality {
+        bins.quality = QualBins::parse_edges(edges)?;
+    }
+    Ok(Some(bins))
+}
+
+fn check_stdin_once(infiles: &[String]) -> Result<(), Box<dyn Error>> {
+    if infiles.iter().filter(|f| *f == "-").count() > 1 {
+        return Err("stdin (-) can only be read once".into());
+    }
+    Ok(())
+}
+
+impl Command {
+    pub fn from_cli() -> Result<Command, Box<dyn Error>> {
+        // Specify CLI, the legacy interface takes effect without a subcommand
         let matches = clap_app!(readfilter =>
         (version: "0.1")
         (author: "Stefan Holderbach")
         (about: "Read filter for amplicon sequencing with a defined region")
+        (usage: "read_filter [FLAGS] [OPTIONS] --config <CONFIG> <INPUT>... <OUTPUT>\n    read_filter <SUBCOMMAND> [OPTIONS] --config <CONFIG> [INPUT]...")
+        (@setting SubcommandsNegateReqs)
+        (@setting ArgsNegateSubcommands)
+        (@setting VersionlessSubcommands)
         (@arg CONFIG: -c --config +takes_value +required "Sets a custom config file")
-        (@arg INPUT: +required "Sets the input file to use")
-        (@arg OUTPUT: +required "Sets the output path")
+        (@arg sample_name: -s --("sample-name") +takes_value "Sets the basename of the outputs, required for several inputs or stdin")
+        (@arg output_template: -o --("output-template") +takes_value "Sets the file names of the outputs, placeholders: {sample}, {kind} and {ext} [default: {sample}.{kind}.{ext}]")
+        (@arg compress: -z --compress +takes_value +multiple number_of_values(1) "Compresses all outputs or, given as KIND=FORMAT, a single one, FORMAT is gz, zstd or none [default: inferred from the file name]")
+        (@arg force: --force "Overwrite existing outputs")
+        // A single positional, clap 2 cannot place a positional after one with multiple values
+        (@arg PATHS: +required +multiple min_values(2) value_name("PATH") "Sets the input files to use (- for stdin) followed by the output path")
         (@arg read_report: -r --("read-report") "Also output a table with QC information for each read")
         (@arg qc_report: -q --("qc-report") "Also output a table with overall QC information")
+        (@arg fastq_export: -f --("fastq-export") "Also output the matching inserts as FASTQ")
+        (@arg diversity_json: --("diversity-json") "Also output the diversity metrics as JSON")
+        (@arg read_qc: --("read-qc") "Also output QC information of the whole reads")
+        (@arg html_report: --("html-report") "Also output a self-contained HTML QC report")
+        (@arg multiqc: --multiqc "Also output the read accounting, insert quality and diversity for MultiQC")
+        (@arg provenance: --provenance "Also output the provenance of the run and the list of its outputs as JSON")
         (@arg debug: -d ... "Sets the level of debugging information")
-    ).get_matches();
-        // Unpack arguments
-        let infile = matches
-            .value_of("INPUT")
-            .unwrap_graceful("Missing inputfile");
-        let outdir = matches
-            .value_of("OUTPUT")
-            .unwrap_graceful("Missing output directory");
-        let config_file = matches
-            .value_of("CONFIG")
-            .unwrap_graceful("Missing config file");
-        let rr_required = matches.is_present("read_report");
-        let qc_required = matches.is_present("qc_report");
+    )
+        .arg(set_arg())
+        .args(&counts_format_args())
+        .arg(table_format_arg("Sets the format of the counts table and the read report, json writes JSON Lines for the read report"))
+        .args(&qual_bins_args())
+        .subcommand(
+            stream_subcommand("count", "Writes the counts of the inserts")
+                .arg(Arg::from_usage(
+                    "--diversity-json [FILE] 'Also writes the diversity metrics as JSON'",
+                ))
+                .args(&counts_format_args())
+                .arg(table_format_arg("Sets the format of the counts table"))
+                .args(&multiqc_args()),
+        )
+        .subcommand(stream_subcommand("extract", "Writes the accepted inserts as FASTQ"))
+        .subcommand(
+            stream_subcommand("qc", "Writes the overall QC report")
+                .arg(Arg::from_usage(
+                    "--position-quality [FILE] 'Also writes the quality distribution per insert position'",
+                ))
+                .arg(Arg::from_usage(
+                    "--read-qc [FILE] 'Also writes QC information of the whole reads'",
+                ))
+                .args(&qual_bins_args())
+                .args(&multiqc_args()),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Merges the counts tables of several samples into a matrix")
+                .args(&output_args())
+                .arg(provenance_arg())
+                .args_from_usage(
+                    "--long 'Writes one line per insert and sample with a nonzero count instead of the matrix'
+                    --lenient 'Only requires equal flanks and insert lengths, allowing different windows and quality thresholds'
+                    <TABLE>... 'Sets the counts tables to merge, the sample names are taken from the file names'",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("enrich")
+                .about("Scores the log2 enrichment of the inserts between selection rounds")
+                .args(&output_args())
+                .arg(provenance_arg())
+                .args(&[
+                    Arg::from_usage("--pseudocount [PSEUDOCOUNT] 'Sets the pseudocount added to all counts'")
+                        .default_value("0.5"),
+                    Arg::from_usage("--error [MODEL] 'Sets the model of the standard errors'")
+                        .possible_values(&["poisson", "binomial"])
+                        .default_value("poisson"),
+                    Arg::from_usage("-w, --wild-type [SEQ] 'Normalizes by the counts of the wild-type insert instead of the total counts'"),
+                    Arg::from_usage("-m, --min-input-count [N] 'Skips inserts with fewer reads in the first round'")
+                        .default_value("0"),
+                    Arg::from_usage("--lenient 'Only requires equal flanks and insert lengths, allowing different windows and quality thresholds'"),
+                    Arg::from_usage("<TABLE>... 'Sets the counts tables of the rounds, starting with the input'")
+                        .min_values(2),
+                ]),
+        )
+        .subcommand(
+            stream_subcommand("calibrate", "Suggests expect_begin and tolerance from the flank positions of the first reads")
+                .args(&[
+                    Arg::from_usage("-n, --reads [N] 'Sets the number of reads to scan'")
+                        .default_value("100000"),
+                    Arg::from_usage("--coverage [FRACTION] 'Sets the fraction of flank hits the window has to cover'")
+                        .default_value("0.995"),
+                    Arg::from_usage("--write-config [FILE] 'Also writes the config with the suggested window'"),
+                ]),
+        )
+        .get_matches();
+        match matches.subcommand() {
+            ("count", Some(sub)) => Ok(Command::Count(StreamConfig::from_matches(sub)?)),
+            ("extract", Some(sub)) => Ok(Command::Extract(StreamConfig::from_matches(sub)?)),
+            ("qc", Some(sub)) => Ok(Command::Qc(StreamConfig::from_matches(sub)?)),
+            ("merge", Some(sub)) => Ok(Command::Merge(MergeConfig::from_matches(sub)?)),
+            ("enrich", Some(sub)) => Ok(Command::Enrich(EnrichConfig::from_matches(sub)?)),
+            ("calibrate", Some(sub)) => Ok(Command::Calibrate(CalibrateConfig::from_matches(sub)?)),
+            _ => Ok(Command::Legacy(ProgConfig::from_matches(&matches)?)),
+        }
+    }
+}
 
-        let json_config =
-            load_json_config(config_file).unwrap_formatful("While parsing configuration");
+impl OutputFile {
+    fn from_matches(matches: &ArgMatches) -> Result<OutputFile, Box<dyn Error>> {
+        let path = matches
+            .value_of("output")
+            .unwrap_graceful("Missing output")
+            .to_string();
+        let compression = match matches.value_of("compress") {
+            Some(format) => format.parse()?,
+            None if path == "-" => Compression::None,
+            None => Compression::from_path(&path),
+        };
+        if compression == Compression::None && Compression::from_path(&path) != Compression::None {
+            return Err(format!(
+                "Output {} is named compressed, which the chosen compression none contradicts",
+                path
+            )
+            .into());
+        }
+        Ok(OutputFile {
+            path,
+            compression,
+            force: matches.is_present("force"),
+        })
+    }
+}
 
-        // TODO: Add checks to block useless or illegal inputs/configs
-        // Category illegal:
-        // pattern larger than 64 (shift_and or bndm wouldn't be able to construct the match vector)
-        // Category useless:
-        // If read length would be known, exptected start + total pattern length beyond read_length
+impl StreamConfig {
+    fn from_matches(matches: &ArgMatches) -> Result<StreamConfig, Box<dyn Error>> {
+        let infiles: Vec<String> = matches
+            .values_of("INPUT")
+            .unwrap_graceful("Missing inputfile")
+            .map(String::from)
+            .collect();
+        check_stdin_once(&infiles)?;
+        let output = OutputFile::from_matches(matches)?;
+        let table_format = table_format_from_matches(matches)?;
+        if table_format.is_columnar() && output.compression != Compression::None {
+            return Err(
+                "Parquet and Arrow outputs are compressed internally, leave out --compress and the compression suffix".into(),
+            );
+        }
+        let multiqc = match matches.value_of("multiqc") {
+            Some(outdir) => Some(MultiqcOutput {
+                outdir: outdir.to_string(),
+                sample_name: sample_name_from_matches(matches, &infiles)?,
+            }),
+            None => None,
+        };
+        Ok(StreamConfig {
+            infiles,
+            output,
+            table_format,
+            diversity_json: matches.value_of("diversity-json").map(String::from),
+            read_qc: matches.value_of("read-qc").map(String::from),
+            position_quality: matches.value_of("position-quality").map(String::from),
+            provenance: matches.value_of("provenance").map(String::from),
+            multiqc,
+            counts_format: counts_format_from_matches(matches)?,
+            qual_bins: qual_bins_from_matches(matches)?,
+            filter: filter_from_matches(matches),
+        })
+    }
+}
+
+impl MergeConfig {
+    fn from_matches(matches: &ArgMatches) -> Result<MergeConfig, Box<dyn Error>> {
+        Ok(MergeConfig {
+            tables: matches
+                .values_of("TABLE")
+                .unwrap_graceful("Missing counts table")
+                .map(String::from)
+                .collect(),
+            output: OutputFile::from_matches(matches)?,
+            long: matches.is_present("long"),
+            lenient: matches.is_present("lenient"),
+            provenance: matches.value_of("provenance").map(String::from),
+        })
+    }
+}
+
+impl EnrichConfig {
+    fn from_matches(matches: &ArgMatches) -> Result<EnrichConfig, Box<dyn Error>> {
+        let pseudocount: f64 = matches
+            .value_of("pseudocount")
+            .unwrap_graceful("Missing pseudocount")
+            .parse()
+            .map_err(|e| format!("Invalid pseudocount: {}", e))?;
+        if !pseudocount.is_finite() || pseudocount <= 0. {
+            return Err("The pseudocount has to be a positive number".into());
+        }
+        let params = EnrichParams {
+            pseudocount,
+            error: matches
+                .value_of("error")
+                .unwrap_graceful("Missing error model")
+                .parse()?,
+            wild_type: matches.value_of("wild-type").map(String::from),
+            min_input_count: matches
+                .value_of("min-input-count")
+                .unwrap_graceful("Missing minimal input count")
+                .parse()
+                .map_err(|e| format!("Invalid minimal input count: {}", e))?,
+        };
+        Ok(EnrichConfig {
+            tables: matches
+                .values_of("TABLE")
+                .unwrap_graceful("Missing counts table")
+                .map(String::from)
+                .collect(),
+            output: OutputFile::from_matches(matches)?,
+            lenient: matches.is_present("lenient"),
+            provenance: matches.value_of("provenance").map(String::from),
+            params,
+        })
+    }
+}
 
-        // TODO: Make positional limits optional (assumption only the valid entity will match the full pattern and quality based ranking is unnecessary)
+impl CalibrateConfig {
+    fn from_matches(matches: &ArgMatches) -> Result<CalibrateConfig, Box<dyn Error>> {
+        let infiles: Vec<String> = matches
+            .values_of("INPUT")
+            .unwrap_graceful("Missing inputfile")
+            .map(String::from)
+            .collect();
+        check_stdin_once(&infiles)?;
+        let coverage: f64 = matches
+            .value_of("coverage")
+            .unwrap_graceful("Missing coverage")
+            .parse()
+            .map_err(|e| format!("Invalid coverage: {}", e))?;
+        if !(coverage > 0. && coverage <= 1.) {
+            return Err("The coverage has to be a fraction between 0 and 1".into());
+        }
+        Ok(CalibrateConfig {
+            infiles,
+            output: OutputFile::from_matches(matches)?,
+            reads: matches
+                .value_of("reads")
+                .unwrap_graceful("Missing number of reads")
+                .parse()
+                .map_err(|e| format!("Invalid number of reads: {}", e))?,
+            coverage,
+            write_config: matches.value_of("write-config").map(String::from),
+            provenance: matches.value_of("provenance").map(String::from),
+            filter: filter_from_matches(matches),
+        })
+    }
+}
+
+impl ProgConfig {
+    fn from_matches(matches: &ArgMatches) -> Result<ProgConfig, Box<dyn Error>> {
+        // Unpack arguments
+        let mut infiles: Vec<String> = matches
+            .values_of("PATHS")
+            .unwrap_graceful("Missing inputfile")
+            .map(String::from)
+            .collect();
+        let outdir = infiles.pop().unwrap_graceful("Missing output directory");
+        check_stdin_once(&infiles)?;
+        let sample_name = sample_name_from_matches(matches, &infiles)?;
+        let output_template = match matches.value_of("output_template") {
+            Some(template) => OutputTemplate::new(template)?,
+            None => OutputTemplate::default(),
+        };
+        let mut compression = CompressionChoice::default();
+        for arg in matches.values_of("compress").into_iter().flatten() {
+            compression.push_arg(arg)?;
+        }
+        let table_format = table_format_from_matches(matches)?;
+        output_template.check_compression(&compression, table_format)?;
+        let force = matches.is_present("force");
+        let rr_required = matches.is_present("read_report");
+        let qual_bins = qual_bins_from_matches(matches)?;
+        // Asking for bins implies the QC report
+        let qc_required = matches.is_present("qc_report") || qual_bins.is_some();
+        let fastq_required = matches.is_present("fastq_export");
+        let diversity_required = matches.is_present("diversity_json");
+        let read_qc_required = matches.is_present("read_qc");
+        let multiqc_required = matches.is_present("multiqc");
+        let html_required = matches.is_present("html_report");
+        let provenance_required = matches.is_present("provenance");
 
         Ok(ProgConfig {
-            infile: infile.to_string(),
-            outdir: outdir.to_string(),
+            infiles,
+            outdir,
+            sample_name,
+            output_template,
+            compression,
+            force,
             rr_required,
             qc_required,
-            left_flank: json_config.left_flank,
-            right_flank: json_config.right_flank,
-            insert_length: json_config.content_length,
-            expected_start: json_config.expect_begin,
-            position_tolerance: json_config.tolerance,
-            min_peak_qual: json_config.qual_peak,
-            min_mean_qual: json_config.qual_mean,
+            fastq_required,
+            diversity_required,
+            read_qc_required,
+            multiqc_required,
+            html_required,
+            provenance_required,
+            counts_format: counts_format_from_matches(matches)?,
+            table_format,
+            qual_bins,
+            filter: filter_from_matches(matches),
         })
     }
 }
@@ -0,0 +1,203 @@
+//! Library diversity and coverage metrics of the counted inserts
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io;
+
+use counter::Counter;
+use serde::Serialize;
+
+/// Numbers of top variants whose share of the reads is reported
+pub const TOP_N: [usize; 3] = [1, 10, 100];
+
+/// Bases allowed at one position of a design template in IUPAC notation
+fn iupac_bases(code: u8) -> Option<&'static [u8]> {
+    let bases: &'static [u8] = match code {
+        b'A' => b"A",
+        b'C' => b"C",
+        b'G' => b"G",
+        b'T' => b"T",
+        b'R' => b"AG",
+        b'Y' => b"CT",
+        b'S' => b"CG",
+        b'W' => b"AT",
+        b'K' => b"GT",
+        b'M' => b"AC",
+        b'B' => b"CGT",
+        b'D' => b"AGT",
+        b'H' => b"ACT",
+        b'V' => b"ACG",
+        b'N' => b"ACGT",
+        _ => return None,
+    };
+    Some(bases)
+}
+
+/// Degenerate template of the designed library, e.g. `NNKNNKNNK`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DesignTemplate(Vec<&'static [u8]>);
+
+impl DesignTemplate {
+    /// Parses the IUPAC codes of a template covering the whole insert
+    pub fn new(template: &str, insert_length: usize) -> Result<Self, Box<dyn Error>> {
+        if template.len() != insert_length {
+            return Err(format!(
+                "The design template needs to be {} nt long like the insert",
+                insert_length
+            )
+            .into());
+        }
+        template
+            .bytes()
+            .map(|code| {
+                iupac_bases(code.to_ascii_uppercase()).ok_or_else(|| {
+                    format!(
+                        "Invalid IUPAC code {:?} in the design template",
+                        code as char
+                    )
+                    .into()
+                })
+            })
+            .collect::<Result<_, _>>()
+            .map(DesignTemplate)
+    }
+
+    /// Number of distinct inserts of the designed library
+    pub fn size(&self) -> f64 {
+        self.0.iter().map(|bases| bases.len() as f64).product()
+    }
+
+    pub fn matches(&self, seq: &[u8]) -> bool {
+        seq.len() == self.0.len() && seq.iter().zip(&self.0).all(|(c, bases)| bases.contains(c))
+    }
+}
+
+/// Coverage of the designed library by the observed inserts
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DesignCoverage {
+    pub design_size: f64,
+    /// Distinct observed inserts allowed by the template
+    pub observed_designs: usize,
+    pub coverage: f64,
+    /// Share of the reads with an insert allowed by the template
+    pub reads_in_design: f64,
+}
+
+/// Summary of the counted inserts
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Diversity {
+    pub reads: usize,
+    pub unique_inserts: usize,
+    /// Natural logarithm
+    pub shannon_entropy: f64,
+    /// Probability of two reads carrying the same insert
+    pub simpson_index: f64,
+    pub chao1: f64,
+    /// Share of the reads in the `n` most abundant inserts, for each `n` in `TOP_N`
+    pub top_fractions: BTreeMap<usize, f64>,
+    pub design: Option<DesignCoverage>,
+}
+
+impl Diversity {
+    pub fn new(counter: &Counter<Vec<u8>>, design: Option<&DesignTemplate>) -> Self {
+        let reads: usize = counter.values().sum();
+        let total = reads.max(1) as f64;
+        let mut shannon_entropy = 0.;
+        let mut simpson_index = 0.;
+        let (mut singletons, mut doubletons) = (0., 0.);
+        for &count in counter.values() {
+            let p = count as f64 / total;
+            shannon_entropy -= p * p.ln();
+            simpson_index += p * p;
+            match count {
+                1 => singletons += 1.,
+                2 => doubletons += 1.,
+                _ => {}
+            }
+        }
+        let unique_inserts = counter.len();
+        // Bias corrected form without doubletons
+        let chao1 = if doubletons > 0. {
+            unique_inserts as f64 + singletons * singletons / (2. * doubletons)
+        } else {
+            unique_inserts as f64 + singletons * (singletons - 1.) / 2.
+        };
+        let mut counts: Vec<usize> = counter.values().copied().collect();
+        counts.sort_unstable_by(|a, b| b.cmp(a));
+        let top_fractions = TOP_N
+            .iter()
+            .map(|&n| (n, counts.iter().take(n).sum::<usize>() as f64 / total))
+            .collect();
+        let design = design.map(|template| {
+            let (observed_designs, design_reads) = counter
+                .iter()
+                .filter(|(seq, _)| template.matches(seq))
+                .fold((0, 0), |(n, r), (_, count)| (n + 1, r + count));
+            DesignCoverage {
+                design_size: template.size(),
+                observed_designs,
+                coverage: observed_designs as f64 / template.size(),
+                reads_in_design: design_reads as f64 / total,
+            }
+        });
+        Diversity {
+            reads,
+            unique_inserts,
+            shannon_entropy,
+            simpson_index,
+            chao1,
+            top_fractions,
+            design,
+        }
+    }
+
+    /// Header lines in the style of `write_stats_header`
+    pub fn write_header<W: io::Write>(&self, buf: &mut W) -> io::Result<()> {
+        writeln!(buf, "# unique_inserts: {}", self.unique_inserts)?;
+        writeln!(buf, "# shannon_entropy: {:.4}", self.shannon_entropy)?;
+        writeln!(buf, "# simpson_index: {:.6}", self.simpson_index)?;
+        writeln!(buf, "# chao1: {:.1}", self.chao1)?;
+        for (n, fraction) in &self.top_fractions {
+            writeln!(buf, "# top_{}_fraction: {:.4}", n, fraction)?;
+        }
+        if let Some(design) = &self.design {
+            writeln!(buf, "# design_size: {}", design.design_size)?;
+            writeln!(buf, "# observed_designs: {}", design.observed_designs)?;
+            // Large designs are covered by tiny fractions
+            if design.coverage > 0. && design.coverage < 1e-4 {
+                writeln!(buf, "# design_coverage: {:.4e}", design.coverage)?;
+            } else {
+                writeln!(buf, "# design_coverage: {:.6}", design.coverage)?;
+            }
+            writeln!(buf, "# reads_in_design: {:.4}", design.reads_in_design)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_diversity() {
+        let mut counter = Counter::new();
+        for (seq, count) in [("AAG", 4), ("CCT", 2), ("GGA", 1), ("TTT", 1)] {
+            counter.insert(seq.as_bytes().to_vec(), count);
+        }
+        let template = DesignTemplate::new("NNK", 3).unwrap();
+        assert_eq!(template.size(), 32.);
+        let div = Diversity::new(&counter, Some(&template));
+        assert_eq!(div.reads, 8);
+        assert_eq!(div.unique_inserts, 4);
+        assert!((div.simpson_index - (16. + 4. + 1. + 1.) / 64.).abs() < 1e-12);
+        // Two singletons and one doubleton
+        assert!((div.chao1 - 6.).abs() < 1e-12);
+        assert_eq!(div.top_fractions[&1], 0.5);
+        let design = div.design.unwrap();
+        assert_eq!(design.observed_designs, 3);
+        assert!((design.reads_in_design - 7. / 8.).abs() < 1e-12);
+
+        assert!(DesignTemplate::new("NNX", 3).is_err());
+        assert!(DesignTemplate::new("NN", 3).is_err());
+    }
+}
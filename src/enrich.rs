@@ -0,0 +1,274 @@
+//! Log2 enrichment of the inserts between selection rounds
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::merge::CountMatrix;
+
+/// Approximation of the standard error of a log2 ratio
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorModel {
+    /// Counts and totals as independent Poisson variables
+    Poisson,
+    /// Counts as binomial draws from the totals
+    Binomial,
+}
+
+impl fmt::Display for ErrorModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorModel::Poisson => write!(f, "poisson"),
+            ErrorModel::Binomial => write!(f, "binomial"),
+        }
+    }
+}
+
+impl FromStr for ErrorModel {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poisson" => Ok(ErrorModel::Poisson),
+            "binomial" => Ok(ErrorModel::Binomial),
+            _ => Err(format!("Unknown error model {:?}, use poisson or binomial", s).into()),
+        }
+    }
+}
+
+/// Parameters of the enrichment scoring
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnrichParams {
+    /// Added to every count and reference count, positive to keep zero counts finite
+    pub pseudocount: f64,
+    pub error: ErrorModel,
+    /// Normalize by this insert instead of the total counts of a round
+    pub wild_type: Option<String>,
+    /// Inserts with fewer reads in the first round are skipped
+    pub min_input_count: u64,
+}
+
+impl Default for EnrichParams {
+    fn default() -> Self {
+        EnrichParams {
+            pseudocount: 0.5,
+            error: ErrorModel::Poisson,
+            wild_type: None,
+            min_input_count: 0,
+        }
+    }
+}
+
+/// Log2 ratio and its standard error of an insert between the input and a selected round
+///
+/// `input` and `selected` pair the count of the insert with the reference count of the round,
+/// its total or wild-type count.
+/// Relative to the wild type both error models reduce to the log odds of the insert against the wild type.
+pub fn log2_enrichment(
+    input: (u64, u64),
+    selected: (u64, u64),
+    params: &EnrichParams,
+) -> (f64, f64) {
+    let pc = params.pseudocount;
+    let (c0, r0) = (input.0 as f64 + pc, input.1 as f64 + pc);
+    let (c1, r1) = (selected.0 as f64 + pc, selected.1 as f64 + pc);
+    let ratio = ((c1 / r1) / (c0 / r0)).log2();
+    let variance = match (params.error, params.wild_type.is_some()) {
+        (ErrorModel::Binomial, false) => (1. / c1 - 1. / r1) + (1. / c0 - 1. / r0),
+        _ => 1. / c1 + 1. / r1 + 1. / c0 + 1. / r0,
+    };
+    (ratio, variance.max(0.).sqrt() / std::f64::consts::LN_2)
+}
+
+/// Enrichment of every later round relative to the first one, checked before anything is written
+pub struct Enrichment<'a> {
+    matrix: &'a CountMatrix,
+    params: &'a EnrichParams,
+    /// Total or wild-type count of each round
+    references: Vec<u64>,
+}
+
+impl<'a> Enrichment<'a> {
+    /// Fails for less than two rounds or a wild type missing from the counts tables
+    pub fn new(matrix: &'a CountMatrix, params: &'a EnrichParams) -> Result<Self, Box<dyn Error>> {
+        let samples = matrix.samples();
+        if samples.len() < 2 {
+            return Err("Enrichment requires at least two rounds".into());
+        }
+        let references: Vec<u64> = match &params.wild_type {
+            Some(wt) => matrix
+                .rows()
+                .find(|(seq, _)| *seq == wt)
+                .map(|(_, counts)| counts.clone())
+                .ok_or_else(|| format!("Wild type {} is not part of the counts tables", wt))?,
+            None => samples.iter().map(|(_, t)| t.counted_reads()).collect(),
+        };
+        Ok(Enrichment {
+            matrix,
+            params,
+            references,
+        })
+    }
+
+    /// Writes the counts of all rounds and the log2 enrichment with its standard error
+    pub fn write<W: io::Write>(&self, buf: &mut W) -> io::Result<()> {
+        let (matrix, params) = (self.matrix, self.params);
+        let samples = matrix.samples();
+        matrix.write_sample_headers(buf)?;
+        writeln!(
+            buf,
+            "# enrichment: reference={reference}\tpseudocount={pc}\terror={error}\tmin_input_count={min}",
+            reference = params.wild_type.as_deref().unwrap_or("total"),
+            pc = params.pseudocount,
+            error = params.error,
+            min = params.min_input_count,
+        )?;
+        write!(buf, "seq")?;
+        for (name, _) in samples {
+            write!(buf, "\tcount_{}", name)?;
+        }
+        for (name, _) in &samples[1..] {
+            write!(buf, "\tlog2_enrichment_{name}\tse_{name}", name = name)?;
+        }
+        writeln!(buf)?;
+        for (seq, counts) in matrix.rows() {
+            if counts[0] < params.min_input_count {
+                continue;
+            }
+            write!(buf, "{}", seq)?;
+            for count in counts {
+                write!(buf, "\t{}", count)?;
+            }
+            for round in 1..samples.len() {
+                let (ratio, se) = log2_enrichment(
+                    (counts[0], self.references[0]),
+                    (counts[round], self.references[round]),
+                    params,
+                );
+                write!(buf, "\t{:.4}\t{:.4}", ratio, se)?;
+            }
+            writeln!(buf)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FilterBuilder;
+    use crate::merge::CountsTable;
+    use crate::output::{
+        write_config_header, write_counts_table, write_stats_header, CountsFormat,
+    };
+    use crate::stat::RunningStats;
+    use counter::Counter;
+
+    fn table(counts: &[(&str, u64)]) -> CountsTable {
+        let cfg = FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+            .build()
+            .unwrap();
+        let mut buf = Vec::new();
+        write_config_header(&mut buf, &cfg).unwrap();
+        write_stats_header(&mut buf, &RunningStats::default()).unwrap();
+        buf.extend_from_slice(b"seq\treads\n");
+        for (seq, reads) in counts {
+            buf.extend_from_slice(format!("{}\t{}\n", seq, reads).as_bytes());
+        }
+        CountsTable::from_reader(&buf[..]).unwrap()
+    }
+
+    #[test]
+    fn test_log2_enrichment() {
+        let params = EnrichParams {
+            pseudocount: 1.,
+            ..Default::default()
+        };
+        // Frequency doubles from 9/999 to 39/1999 before adding the pseudocount
+        let (ratio, se) = log2_enrichment((9, 999), (39, 1999), &params);
+        assert!((ratio - 1.).abs() < 1e-9);
+        let variance: f64 = 1. / 40. + 1. / 2000. + 1. / 10. + 1. / 1000.;
+        let expected = variance.sqrt() / 2f64.ln();
+        assert!((se - expected).abs() < 1e-9);
+
+        let binomial = EnrichParams {
+            error: ErrorModel::Binomial,
+            ..params.clone()
+        };
+        let (_, se_binomial) = log2_enrichment((9, 999), (39, 1999), &binomial);
+        assert!(se_binomial < se);
+
+        // The pseudocount keeps inserts missing in the input finite
+        let (ratio, _) = log2_enrichment((0, 1000), (5, 1000), &EnrichParams::default());
+        assert!(ratio.is_finite() && ratio > 3.);
+    }
+
+    #[test]
+    fn test_enrichment_references() {
+        let rounds = || {
+            vec![
+                ("r0".into(), table(&[("AAAAA", 9), ("CCCCC", 9)])),
+                ("r1".into(), table(&[("AAAAA", 39), ("CCCCC", 9)])),
+            ]
+        };
+        let matrix = CountMatrix::merge(rounds(), false).unwrap();
+        let wild_type = EnrichParams {
+            wild_type: Some("CCCCC".into()),
+            pseudocount: 1.,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        Enrichment::new(&matrix, &wild_type)
+            .unwrap()
+            .write(&mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("\nAAAAA\t9\t39\t2.0000\t"));
+
+        // A missing wild type fails before anything is written
+        let missing = EnrichParams {
+            wild_type: Some("GGGGG".into()),
+            ..Default::default()
+        };
+        assert!(Enrichment::new(&matrix, &missing).is_err());
+        let single = CountMatrix::merge(rounds().split_off(1), false).unwrap();
+        assert!(Enrichment::new(&single, &EnrichParams::default()).is_err());
+    }
+
+    #[test]
+    fn test_enrichment_of_shortened_tables() {
+        // Written as by --top 1, the totals still cover the left out inserts
+        let top = |counts: &[(&str, usize)]| {
+            let counter: Counter<Vec<u8>> = counts
+                .iter()
+                .map(|(seq, count)| (seq.as_bytes().to_vec(), *count))
+                .collect();
+            let cfg = FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+                .build()
+                .unwrap();
+            let format = CountsFormat {
+                top: Some(1),
+                ..Default::default()
+            };
+            let mut buf = Vec::new();
+            write_config_header(&mut buf, &cfg).unwrap();
+            write_counts_table(&mut buf, &counter, &format).unwrap();
+            CountsTable::from_reader(&buf[..]).unwrap()
+        };
+        let r0 = top(&[("AAAAA", 60), ("CCCCC", 40)]);
+        assert_eq!((r0.counts.len(), r0.counted_reads()), (1, 100));
+        let r1 = top(&[("AAAAA", 20), ("CCCCC", 5), ("GGGGG", 5)]);
+        let matrix = CountMatrix::merge(vec![("r0".into(), r0), ("r1".into(), r1)], false).unwrap();
+        let mut out = Vec::new();
+        Enrichment::new(&matrix, &EnrichParams::default())
+            .unwrap()
+            .write(&mut out)
+            .unwrap();
+        // Frequency grows from 60/100 to 20/30, each count with the pseudocount of 0.5
+        let expected = format!(
+            "\nAAAAA\t60\t20\t{:.4}\t",
+            (20.5 / 30.5 / (60.5 / 100.5f64)).log2()
+        );
+        assert!(String::from_utf8(out).unwrap().contains(&expected));
+    }
+}
@@ -0,0 +1,286 @@
+//! Self-contained HTML report with inline SVG charts
+//!
+//! Rendered from the same structures as the TSV reports, nothing is fetched when viewing it.
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+
+use counter::Counter;
+
+use crate::config::FilterParams;
+use crate::output::{sorted_counts, write_config_header, write_stats_header, CountsFormat};
+use crate::provenance::Provenance;
+use crate::stat::{QualStats, RunningStats};
+
+/// Number of variants listed in the report
+const TOP_VARIANTS: usize = 20;
+
+const WIDTH: f64 = 640.;
+const HEIGHT: f64 = 220.;
+const MARGIN: f64 = 40.;
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em auto;max-width:720px;color:#222}\
+    h2{border-bottom:1px solid #ccc}table{border-collapse:collapse}\
+    td,th{padding:2px 8px;text-align:right}td.seq{font-family:monospace;text-align:left}\
+    pre{background:#f4f4f4;padding:8px}svg text{font-size:11px}";
+
+/// Data of a run rendered into the report
+pub struct HtmlReport<'a> {
+    pub sample: &'a str,
+    pub filter: &'a FilterParams,
+    pub stats: &'a RunningStats,
+    pub qual_stats: &'a QualStats,
+    pub counter: &'a Counter<Vec<u8>>,
+    pub provenance: &'a Provenance,
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Horizontal bars with their label and value
+fn bar_chart(bars: &[(&str, u32)]) -> String {
+    let max = bars.iter().map(|(_, v)| *v).max().unwrap_or(0).max(1) as f64;
+    let row = 24.;
+    let label_width = 160.;
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">"#,
+        WIDTH,
+        row * bars.len() as f64
+    );
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let y = i as f64 * row;
+        let width = (WIDTH - label_width - 80.) * *value as f64 / max;
+        let _ = write!(
+            svg,
+            r##"<text x="0" y="{ty}">{label}</text><rect x="{x}" y="{y}" width="{w:.1}" height="{h}" fill="#4878a8"/><text x="{vx:.1}" y="{ty}">{value}</text>"##,
+            ty = y + 16.,
+            label = escape(label),
+            x = label_width,
+            y = y + 4.,
+            w = width,
+            h = row - 8.,
+            vx = label_width + width + 4.,
+            value = value,
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Frame with y axis ticks of a chart spanning `y_max`
+fn chart_frame(y_max: f64, xlab: &str, ylab: &str) -> String {
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}"><line x1="{m}" y1="{b}" x2="{r}" y2="{b}" stroke="black"/><line x1="{m}" y1="0" x2="{m}" y2="{b}" stroke="black"/><text x="{cx}" y="{xl}" text-anchor="middle">{xlab}</text><text x="10" y="{cy}" transform="rotate(-90 10 {cy})" text-anchor="middle">{ylab}</text>"#,
+        w = WIDTH,
+        h = HEIGHT + MARGIN,
+        m = MARGIN,
+        b = HEIGHT,
+        r = WIDTH,
+        cx = (WIDTH + MARGIN) / 2.,
+        xl = HEIGHT + 32.,
+        cy = HEIGHT / 2.,
+        xlab = xlab,
+        ylab = ylab,
+    );
+    for i in 0..=4 {
+        let value = y_max * i as f64 / 4.;
+        let _ = write!(
+            svg,
+            r#"<text x="{x}" y="{y:.1}" text-anchor="end">{v}</text>"#,
+            x = MARGIN - 4.,
+            y = HEIGHT - HEIGHT * i as f64 / 4. + 4.,
+            v = value.round(),
+        );
+    }
+    svg
+}
+
+/// Vertical bars of the reads at each insert start of one strand
+fn position_chart(positions: &BTreeMap<u32, u32>, xlab: &str) -> String {
+    let (first, last) = match (positions.keys().next(), positions.keys().last()) {
+        (Some(&first), Some(&last)) => (first, last),
+        _ => return "<p>No accepted reads</p>".to_string(),
+    };
+    let max = *positions.values().max().unwrap_or(&1) as f64;
+    let slot = (WIDTH - MARGIN) / (last - first + 1) as f64;
+    let mut svg = chart_frame(max, xlab, "Reads");
+    for (&pos, &reads) in positions {
+        let height = HEIGHT * reads as f64 / max;
+        let x = MARGIN + (pos - first) as f64 * slot;
+        let _ = write!(
+            svg,
+            r##"<rect x="{x:.1}" y="{y:.1}" width="{w:.1}" height="{h:.1}" fill="#4878a8"><title>{pos}: {reads}</title></rect>"##,
+            x = x,
+            y = HEIGHT - height,
+            w = (slot - 1.).max(1.),
+            h = height,
+            pos = pos,
+            reads = reads,
+        );
+    }
+    for pos in [first, last] {
+        let _ = write!(
+            svg,
+            r#"<text x="{x:.1}" y="{y}" text-anchor="middle">{pos}</text>"#,
+            x = MARGIN + ((pos - first) as f64 + 0.5) * slot,
+            y = HEIGHT + 14.,
+            pos = pos,
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+/// Box plot of the qualities along the insert: median line within the quartile band and the 10th to 90th percentile range
+fn quality_chart(percentiles: &[[usize; 5]]) -> String {
+    if percentiles.is_empty() {
+        return "<p>No accepted reads</p>".to_string();
+    }
+    let q_max = 42.;
+    let slot = (WIDTH - MARGIN) / percentiles.len() as f64;
+    let x = |pos: usize| MARGIN + (pos as f64 + 0.5) * slot;
+    let y = |q: usize| HEIGHT - HEIGHT * (q as f64).min(q_max) / q_max;
+    let mut svg = chart_frame(q_max, "Position in the insert", "PHRED score");
+    for (pos, [p10, q1, _, q3, p90]) in percentiles.iter().enumerate() {
+        let _ = write!(
+            svg,
+            r##"<line x1="{x:.1}" y1="{y10:.1}" x2="{x:.1}" y2="{y90:.1}" stroke="#888"/><rect x="{bx:.1}" y="{y3:.1}" width="{bw:.1}" height="{bh:.1}" fill="#f0c050" stroke="#888"/>"##,
+            x = x(pos),
+            y10 = y(*p10),
+            y90 = y(*p90),
+            bx = x(pos) - slot * 0.35,
+            y3 = y(*q3),
+            bw = slot * 0.7,
+            bh = y(*q1) - y(*q3),
+        );
+    }
+    let median: Vec<String> = percentiles
+        .iter()
+        .enumerate()
+        .map(|(pos, p)| format!("{:.1},{:.1}", x(pos), y(p[2])))
+        .collect();
+    let _ = write!(
+        svg,
+        r##"<polyline points="{}" fill="none" stroke="#c03030" stroke-width="2"/>"##,
+        median.join(" ")
+    );
+    for q in [20, 30] {
+        let _ = write!(
+            svg,
+            r##"<line x1="{m}" y1="{y:.1}" x2="{r}" y2="{y:.1}" stroke="#aaa" stroke-dasharray="4"/><text x="{r}" y="{ty:.1}" text-anchor="end">Q{q}</text>"##,
+            m = MARGIN,
+            r = WIDTH,
+            y = y(q),
+            ty = y(q) - 2.,
+            q = q,
+        );
+    }
+    svg.push_str("</svg>");
+    svg
+}
+
+impl<'a> HtmlReport<'a> {
+    pub fn write_to_buf<W: io::Write>(&self, buf: &mut W) -> io::Result<()> {
+        let stats = self.stats;
+        let accepted = stats.matching_reads - (stats.peak_rejected + stats.mean_rejected);
+        let no_match = stats.total_reads - (stats.matching_reads + stats.ambigiuous_rejected);
+        let accounting = [
+            ("total", stats.total_reads),
+            ("no match", no_match),
+            ("ambiguous", stats.ambigiuous_rejected),
+            ("peak quality rejected", stats.peak_rejected),
+            ("mean quality rejected", stats.mean_rejected),
+            ("accepted", accepted),
+        ];
+        let mut config = Vec::new();
+        self.provenance.write_header(&mut config)?;
+        write_config_header(&mut config, self.filter)?;
+        write_stats_header(&mut config, stats)?;
+
+        writeln!(
+            buf,
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>read_filter: {sample}</title><style>{style}</style></head><body>",
+            sample = escape(self.sample),
+            style = STYLE,
+        )?;
+        writeln!(buf, "<h1>read_filter: {}</h1>", escape(self.sample))?;
+        writeln!(buf, "<h2>Read accounting</h2>\n{}", bar_chart(&accounting))?;
+        // Reverse strand starts are counted from the other end of the read, so each strand gets its own axis
+        writeln!(
+            buf,
+            "<h2>Insert positions</h2>\n<h3>Forward strand</h3>\n{}\n<h3>Reverse strand</h3>\n{}",
+            position_chart(
+                &self.qual_stats.start_positions(false),
+                "Start of the insert in the read"
+            ),
+            position_chart(
+                &self.qual_stats.start_positions(true),
+                "Start of the insert in the reverse complement"
+            ),
+        )?;
+        writeln!(
+            buf,
+            "<h2>Insert quality</h2>\n{}",
+            quality_chart(&self.qual_stats.position_percentiles())
+        )?;
+
+        writeln!(
+            buf,
+            "<h2>Top variants</h2>\n<table><tr><th>insert</th><th>reads</th><th>share</th></tr>"
+        )?;
+        let total: usize = self.counter.values().sum();
+        let top = CountsFormat {
+            top: Some(TOP_VARIANTS),
+            ..Default::default()
+        };
+        for (seq, count) in sorted_counts(self.counter, &top) {
+            writeln!(
+                buf,
+                "<tr><td class=\"seq\">{}</td><td>{}</td><td>{:.2}%</td></tr>",
+                escape(&String::from_utf8_lossy(seq)),
+                count,
+                100. * count as f64 / total.max(1) as f64
+            )?;
+        }
+        writeln!(buf, "</table>")?;
+        writeln!(
+            buf,
+            "<h2>Config</h2>\n<pre>{}</pre>",
+            escape(&String::from_utf8_lossy(&config))
+        )?;
+        writeln!(buf, "</body></html>")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_report_is_self_contained() {
+        let filter = crate::config::FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+            .build()
+            .unwrap();
+        let mut counter = Counter::new();
+        counter.insert(b"ACGTA".to_vec(), 3);
+        let report = HtmlReport {
+            sample: "a<b",
+            filter: &filter,
+            stats: &RunningStats::default(),
+            qual_stats: &QualStats::new(),
+            counter: &counter,
+            provenance: &crate::provenance::Run::start().finish(&[], Some(&filter)),
+        };
+        let mut buf = Vec::new();
+        report.write_to_buf(&mut buf).unwrap();
+        let html = String::from_utf8(buf).unwrap();
+        assert!(html.contains("<h1>read_filter: a&lt;b</h1>"));
+        assert!(html.contains("<td class=\"seq\">ACGTA</td><td>3</td><td>100.00%</td>"));
+        assert!(html.contains("# tool: read_filter"));
+        assert!(!html.contains("<script") && !html.contains("<link"));
+    }
+}
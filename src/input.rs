@@ -0,0 +1,572 @@
+//! Opening (compressed) read files of the supported formats
+use std::cell::RefCell;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+use bio::io::{fasta, fastq};
+use sha2::{Digest, Sha256};
+
+use crate::matching::IntoRecord;
+
+#[cfg(feature = "bam")]
+use bio::alphabets::dna;
+#[cfg(feature = "bam")]
+use noodles_bam as bam;
+#[cfg(feature = "bam")]
+use noodles_sam as sam;
+
+/// Placeholder PHRED score (+33) assigned to reads without qualities
+pub const PLACEHOLDER_QUAL: u8 = b'I';
+
+/// Magic bytes of a decompressed BAM stream
+const BAM_MAGIC: &[u8] = b"BAM\x01";
+/// Record types of SAM header lines, distinguishing them from FASTQ ids
+const SAM_HEADER_TAGS: [&[u8]; 5] = [b"@HD\t", b"@SQ\t", b"@RG\t", b"@PG\t", b"@CO\t"];
+
+/// Record formats accepted as input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFormat {
+    Fastq,
+    /// Without qualities, quality based filters and reports are not available
+    Fasta,
+    /// Unaligned (or aligned) SAM, requires the `bam` feature
+    Sam,
+    /// Unaligned (or aligned) BAM, requires the `bam` feature
+    Bam,
+}
+
+impl fmt::Display for ReadFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadFormat::Fastq => write!(f, "FASTQ"),
+            ReadFormat::Fasta => write!(f, "FASTA"),
+            ReadFormat::Sam => write!(f, "SAM"),
+            ReadFormat::Bam => write!(f, "BAM"),
+        }
+    }
+}
+
+/// Boxed stream of records independent of the input format
+pub type Records = Box<dyn Iterator<Item = fastq::Record>>;
+
+/// FASTA records enter the `ReadFilter` with a constant `PLACEHOLDER_QUAL` quality
+impl IntoRecord for fasta::Record {
+    fn into_record(self) -> Option<fastq::Record> {
+        let qual = vec![PLACEHOLDER_QUAL; self.seq().len()];
+        Some(fastq::Record::with_attrs(
+            self.id(),
+            self.desc(),
+            self.seq(),
+            &qual,
+        ))
+    }
+}
+
+/// Detects the format by the first bytes without consuming the input
+///
+/// SAM is recognized by its header lines or, without header, by tab-separated fields.
+/// An empty input is treated as FASTQ.
+pub fn detect_format<B: BufRead>(reader: &mut B) -> io::Result<ReadFormat> {
+    let buf = reader.fill_buf()?;
+    let first_line = buf.split(|&c| c == b'\n').next().unwrap_or_default();
+    match buf.first() {
+        _ if buf.starts_with(BAM_MAGIC) => Ok(ReadFormat::Bam),
+        _ if SAM_HEADER_TAGS.iter().any(|tag| buf.starts_with(tag)) => Ok(ReadFormat::Sam),
+        Some(b'>') => Ok(ReadFormat::Fasta),
+        Some(b'@') | None => Ok(ReadFormat::Fastq),
+        Some(_) if first_line.contains(&b'\t') => Ok(ReadFormat::Sam),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Input is neither FASTQ, FASTA, SAM nor BAM",
+        )),
+    }
+}
+
+/// Error that ended the records of an input early, shared with the records like the `InputDigest`
+///
+/// Set by SAM/BAM input, FASTQ and FASTA parse errors end the records silently like in `ReadFilter`.
+#[derive(Debug, Default, Clone)]
+pub struct ReadError(Rc<RefCell<Option<io::Error>>>);
+
+impl ReadError {
+    #[cfg(feature = "bam")]
+    fn set(&self, error: io::Error) {
+        self.0.borrow_mut().get_or_insert(error);
+    }
+
+    /// Fails with the error that ended the records, to be called once they are exhausted
+    pub fn check(&self) -> io::Result<()> {
+        match self.0.borrow_mut().take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Records of an input in the format they were parsed from
+pub struct Reads {
+    pub format: ReadFormat,
+    /// Whether the records carry qualities, otherwise they hold `PLACEHOLDER_QUAL` and
+    /// quality based filters and reports are not available
+    pub has_quality: bool,
+    pub records: Records,
+    pub error: ReadError,
+}
+
+/// Detects the format of a decompressed stream and parses it
+///
+/// ParseErrors end the stream silently like in `ReadFilter`, except for SAM/BAM input where they are kept as `ReadError`.
+/// SAM/BAM input has qualities unless its first primary record lacks them.
+pub fn records_from_reader<R: Read + 'static>(reader: R) -> io::Result<Reads> {
+    let mut reader = BufReader::new(reader);
+    let format = detect_format(&mut reader)?;
+    let error = ReadError::default();
+    let (has_quality, records): (bool, Records) = match format {
+        ReadFormat::Fastq => (
+            true,
+            Box::new(
+                fastq::Reader::from_bufread(reader)
+                    .records()
+                    .map_while(IntoRecord::into_record),
+            ),
+        ),
+        ReadFormat::Fasta => (
+            false,
+            Box::new(
+                fasta::Reader::from_bufread(reader)
+                    .records()
+                    .map_while(|rec| rec.ok().and_then(IntoRecord::into_record)),
+            ),
+        ),
+        #[cfg(feature = "bam")]
+        ReadFormat::Sam => {
+            let records = AlignmentRecords::new(SamRecords::new(reader)?, error.clone());
+            (records.has_quality, Box::new(records))
+        }
+        #[cfg(feature = "bam")]
+        ReadFormat::Bam => {
+            let records = AlignmentRecords::new(BamRecords::new(reader)?, error.clone());
+            (records.has_quality, Box::new(records))
+        }
+        #[cfg(not(feature = "bam"))]
+        ReadFormat::Sam | ReadFormat::Bam => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} input requires read_filter built with the `bam` feature",
+                    format
+                ),
+            ))
+        }
+    };
+    Ok(Reads {
+        format,
+        has_quality,
+        records,
+        error,
+    })
+}
+
+/// Fields of a primary SAM/BAM record, `qual` holds PHRED scores without offset
+#[cfg(feature = "bam")]
+struct Alignment {
+    name: String,
+    seq: Vec<u8>,
+    /// `None` if the record has no qualities
+    qual: Option<Vec<u8>>,
+    reverse: bool,
+}
+
+#[cfg(feature = "bam")]
+impl Alignment {
+    fn new(name: Option<&[u8]>, seq: Vec<u8>, qual: &[u8], reverse: bool) -> Self {
+        // Missing qualities are stored as 0xFF in BAM and as `*`, read as empty, in SAM
+        let qual = if qual.len() == seq.len() && qual.iter().any(|&q| q != 0xFF) {
+            Some(qual.to_vec())
+        } else {
+            None
+        };
+        Alignment {
+            name: String::from_utf8_lossy(name.unwrap_or_default()).into_owned(),
+            seq,
+            qual,
+            reverse,
+        }
+    }
+}
+
+/// Converts SAM/BAM records to the original reads
+///
+/// Records flagged as reverse complemented are turned back to the sequenced orientation.
+/// Whether the input has qualities is decided by the first record: without them every read gets
+/// `PLACEHOLDER_QUAL`, with them a later record lacking qualities is an error.
+/// Errors end the stream and are kept in the `ReadError`.
+#[cfg(feature = "bam")]
+struct AlignmentRecords<I> {
+    alignments: I,
+    first: Option<io::Result<Alignment>>,
+    has_quality: bool,
+    error: ReadError,
+}
+
+#[cfg(feature = "bam")]
+impl<I: Iterator<Item = io::Result<Alignment>>> AlignmentRecords<I> {
+    fn new(mut alignments: I, error: ReadError) -> Self {
+        let first = alignments.next();
+        let has_quality = match &first {
+            Some(Ok(aln)) => aln.qual.is_some(),
+            _ => true,
+        };
+        AlignmentRecords {
+            alignments,
+            first,
+            has_quality,
+            error,
+        }
+    }
+}
+
+#[cfg(feature = "bam")]
+impl<I: Iterator<Item = io::Result<Alignment>>> Iterator for AlignmentRecords<I> {
+    type Item = fastq::Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let aln = match self.first.take().or_else(|| self.alignments.next())? {
+            Ok(aln) => aln,
+            Err(e) => {
+                self.error.set(e);
+                return None;
+            }
+        };
+        let mut qual: Vec<u8> = match (aln.qual, self.has_quality) {
+            (Some(qual), true) => qual.iter().map(|q| q.saturating_add(33)).collect(),
+            (None, true) => {
+                self.error.set(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Record {} has no qualities, unlike the first record of the input",
+                        aln.name
+                    ),
+                ));
+                return None;
+            }
+            (_, false) => vec![PLACEHOLDER_QUAL; aln.seq.len()],
+        };
+        let mut seq = aln.seq;
+        if aln.reverse {
+            seq = dna::revcomp(&seq);
+            qual.reverse();
+        }
+        Some(fastq::Record::with_attrs(&aln.name, None, &seq, &qual))
+    }
+}
+
+/// Secondary and supplementary alignments repeat a read and are skipped
+#[cfg(feature = "bam")]
+fn is_primary(flags: sam::alignment::record::Flags) -> bool {
+    !(flags.is_secondary() || flags.is_supplementary())
+}
+
+/// Owned iterator over the primary records of a decompressed BAM stream
+#[cfg(feature = "bam")]
+struct BamRecords<R> {
+    reader: bam::io::Reader<R>,
+    record: bam::Record,
+}
+
+#[cfg(feature = "bam")]
+impl<R: Read> BamRecords<R> {
+    fn new(inner: R) -> io::Result<Self> {
+        let mut reader = bam::io::Reader::from(inner);
+        reader.read_header()?;
+        Ok(BamRecords {
+            reader,
+            record: bam::Record::default(),
+        })
+    }
+}
+
+#[cfg(feature = "bam")]
+impl<R: Read> Iterator for BamRecords<R> {
+    type Item = io::Result<Alignment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_record(&mut self.record) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            let flags = self.record.flags();
+            if !is_primary(flags) {
+                continue;
+            }
+            return Some(Ok(Alignment::new(
+                self.record.name().map(|n| n.as_ref()),
+                self.record.sequence().iter().collect(),
+                self.record.quality_scores().as_bytes(),
+                flags.is_reverse_complemented(),
+            )));
+        }
+    }
+}
+
+/// Owned iterator over the primary records of a decompressed SAM stream
+#[cfg(feature = "bam")]
+struct SamRecords<B> {
+    reader: sam::io::Reader<B>,
+    record: sam::Record,
+}
+
+#[cfg(feature = "bam")]
+impl<B: BufRead> SamRecords<B> {
+    fn new(inner: B) -> io::Result<Self> {
+        let mut reader = sam::io::Reader::new(inner);
+        reader.read_header()?;
+        Ok(SamRecords {
+            reader,
+            record: sam::Record::default(),
+        })
+    }
+}
+
+#[cfg(feature = "bam")]
+impl<B: BufRead> Iterator for SamRecords<B> {
+    type Item = io::Result<Alignment>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.reader.read_record(&mut self.record) {
+                Ok(0) => return None,
+                Ok(_) => {}
+                Err(e) => return Some(Err(e)),
+            }
+            let flags = match self.record.flags() {
+                Ok(flags) => flags,
+                Err(e) => return Some(Err(e)),
+            };
+            if !is_primary(flags) {
+                continue;
+            }
+            // SAM stores the qualities with the offset of 33
+            let qual: Vec<u8> = self
+                .record
+                .quality_scores()
+                .as_ref()
+                .iter()
+                .map(|q| q.saturating_sub(33))
+                .collect();
+            return Some(Ok(Alignment::new(
+                self.record.name().map(|n| n.as_ref()),
+                self.record.sequence().as_ref().to_vec(),
+                &qual,
+                flags.is_reverse_complemented(),
+            )));
+        }
+    }
+}
+
+/// Opens a plain or compressed FASTQ, FASTA, SAM or BAM file, see `records_from_reader`
+///
+/// The path `-` reads from stdin.
+pub fn open_reads<P: AsRef<Path>>(path: P) -> Result<Reads, Box<dyn Error>> {
+    let (reader, _compression) = if path.as_ref() == Path::new("-") {
+        niffler::get_reader(Box::new(io::stdin()))?
+    } else {
+        niffler::from_path(path)?
+    };
+    Ok(records_from_reader(reader)?)
+}
+
+/// Size and SHA-256 of the raw, possibly compressed, bytes read from an input
+#[derive(Debug, Default, Clone)]
+pub struct InputDigest {
+    hasher: Sha256,
+    bytes: u64,
+    complete: bool,
+}
+
+impl InputDigest {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Hex digest, `None` unless the input was read to its end
+    pub fn sha256(&self) -> Option<String> {
+        if !self.complete {
+            return None;
+        }
+        Some(format!("{:x}", self.hasher.clone().finalize()))
+    }
+}
+
+/// Passes the bytes of `inner` through the shared `InputDigest`
+struct DigestReader<R> {
+    inner: R,
+    digest: Rc<RefCell<InputDigest>>,
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut digest = self.digest.borrow_mut();
+        if n == 0 && !buf.is_empty() {
+            digest.complete = true;
+        }
+        digest.hasher.update(&buf[..n]);
+        digest.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// Read input opened by `open_reads_with_digest`
+pub struct DigestedReads {
+    pub format: ReadFormat,
+    /// See `Reads::has_quality`
+    pub has_quality: bool,
+    pub compression: niffler::compression::Format,
+    pub records: Records,
+    pub error: ReadError,
+    /// Complete once `records` is exhausted
+    pub digest: Rc<RefCell<InputDigest>>,
+}
+
+/// Decompressed input whose raw bytes pass through the shared `InputDigest`
+pub struct DigestedInput {
+    pub reader: Box<dyn Read>,
+    pub compression: niffler::compression::Format,
+    /// Complete once `reader` is read to its end
+    pub digest: Rc<RefCell<InputDigest>>,
+}
+
+/// Opens a plain or compressed file and tracks the checksum of its raw bytes, `-` reads from stdin
+pub fn open_with_digest<P: AsRef<Path>>(path: P) -> Result<DigestedInput, Box<dyn Error>> {
+    let digest = Rc::new(RefCell::new(InputDigest::default()));
+    let raw: Box<dyn Read> = if path.as_ref() == Path::new("-") {
+        Box::new(io::stdin())
+    } else {
+        Box::new(std::fs::File::open(path)?)
+    };
+    let (reader, compression) = niffler::get_reader(Box::new(DigestReader {
+        inner: raw,
+        digest: Rc::clone(&digest),
+    }))?;
+    Ok(DigestedInput {
+        reader,
+        compression,
+        digest,
+    })
+}
+
+/// Like `open_reads` but also tracks the compression and checksum of the raw input
+pub fn open_reads_with_digest<P: AsRef<Path>>(path: P) -> Result<DigestedReads, Box<dyn Error>> {
+    let input = open_with_digest(path)?;
+    let reads = records_from_reader(input.reader)?;
+    Ok(DigestedReads {
+        format: reads.format,
+        has_quality: reads.has_quality,
+        compression: input.compression,
+        records: reads.records,
+        error: reads.error,
+        digest: input.digest,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fasta_records_are_detected() {
+        let fa: &'static [u8] = b">design_1\nACGT\nACGT\n>design_2\nGGCC\n";
+        let reads = records_from_reader(fa).unwrap();
+        assert_eq!(reads.format, ReadFormat::Fasta);
+        assert!(!reads.has_quality);
+        let records: Vec<_> = reads.records.collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].seq(), b"ACGTACGT");
+        assert_eq!(records[0].qual(), b"IIIIIIII");
+
+        assert!(records_from_reader(&b"ACGT\n"[..]).is_err());
+    }
+
+    #[test]
+    fn test_digest_covers_whole_input() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/sample.fastq");
+        let input = open_reads_with_digest(&path).unwrap();
+        assert_eq!(input.digest.borrow().sha256(), None);
+        assert!(input.records.count() > 0);
+        let raw = std::fs::read(&path).unwrap();
+        let digest = input.digest.borrow();
+        assert_eq!(digest.bytes(), raw.len() as u64);
+        assert_eq!(digest.sha256(), Some(format!("{:x}", Sha256::digest(&raw))));
+    }
+
+    #[cfg(feature = "bam")]
+    #[test]
+    fn test_alignment_fixtures_match_fastq() {
+        let data = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data");
+        let expected: Vec<_> = open_reads(data.join("sample.fastq"))
+            .unwrap()
+            .records
+            .collect();
+        // read_0 is stored reverse complemented, a secondary copy of read_3 is appended
+        for (name, format) in [
+            ("sample.sam", ReadFormat::Sam),
+            ("sample.bam", ReadFormat::Bam),
+        ] {
+            let reads = open_reads(data.join(name)).unwrap();
+            assert_eq!(reads.format, format);
+            assert!(reads.has_quality);
+            let records: Vec<_> = reads.records.collect();
+            assert_eq!(records.len(), expected.len());
+            for (rec, exp) in records.iter().zip(&expected) {
+                assert_eq!(rec.id(), exp.id());
+                assert_eq!(rec.seq(), exp.seq());
+                assert_eq!(rec.qual(), exp.qual());
+            }
+        }
+    }
+
+    #[cfg(feature = "bam")]
+    #[test]
+    fn test_missing_alignment_qualities() {
+        let sam: &'static [u8] = b"@HD\tVN:1.6\n\
+read_0\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t*\n\
+read_1\t4\t*\t0\t0\t*\t*\t0\t0\tGGCC\t*\n";
+        let reads = records_from_reader(sam).unwrap();
+        assert!(!reads.has_quality);
+        let records: Vec<_> = reads.records.collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].qual(), b"IIII");
+
+        // Qualities are not made up for a record lacking them in an input with qualities
+        let mixed: &'static [u8] = b"@HD\tVN:1.6\n\
+read_0\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\t5I5I\n\
+read_1\t4\t*\t0\t0\t*\t*\t0\t0\tGGCC\t*\n";
+        let mut reads = records_from_reader(mixed).unwrap();
+        assert!(reads.has_quality);
+        assert!(reads.error.check().is_ok());
+        let records: Vec<_> = reads.records.by_ref().collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].qual(), b"5I5I");
+        let error = reads.error.check().unwrap_err();
+        assert!(error.to_string().contains("read_1"));
+    }
+
+    #[cfg(feature = "bam")]
+    #[test]
+    fn test_truncated_bam_is_an_error() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/sample.bam");
+        let mut bam = std::fs::read(path).unwrap();
+        bam.truncate(bam.len() - 80);
+        let (reader, _) = niffler::get_reader(Box::new(io::Cursor::new(bam))).unwrap();
+        let reads = records_from_reader(reader).unwrap();
+        assert_eq!(reads.format, ReadFormat::Bam);
+        assert!(reads.records.count() < 8);
+        assert!(reads.error.check().is_err());
+    }
+}
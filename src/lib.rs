@@ -1,9 +1,24 @@
 //! Tool to deal with targeted amplicon sequencing results
+pub mod calibrate;
+pub mod capi;
+#[cfg(feature = "columnar")]
+pub mod columnar;
 pub mod config;
+pub mod diversity;
+pub mod enrich;
 pub mod handling;
+pub mod html;
+pub mod input;
 pub mod match_type;
 pub mod matching;
+pub mod merge;
+pub mod multiqc;
+pub mod naming;
 pub mod output;
+pub mod provenance;
+#[cfg(feature = "python")]
+mod python;
+pub mod sink;
 pub mod stat;
 #[macro_use]
 extern crate clap;
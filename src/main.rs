@@ -1,113 +1,970 @@
-use bio::io::fastq;
 use counter::Counter;
-use csv::WriterBuilder;
-use read_filter::config::ProgConfig;
+use flate2::write::GzEncoder;
+use read_filter::calibrate::PositionHistogram;
+#[cfg(feature = "columnar")]
+use read_filter::columnar::{self, ColumnarReadReportSink};
+use read_filter::config::{
+    CalibrateConfig, Command, ConfigFormat, EnrichConfig, FilterConf, FilterParams, MergeConfig,
+    MultiqcOutput, OutputFile, ProgConfig, StreamConfig,
+};
+use read_filter::diversity::{DesignTemplate, Diversity};
+use read_filter::enrich::Enrichment;
 use read_filter::handling::{GracefulOption, GracefulResult};
+use read_filter::html::HtmlReport;
+use read_filter::input::{
+    open_reads_with_digest, open_with_digest, ReadError, ReadFormat, Records,
+};
+use read_filter::match_type::SearchMatch;
 use read_filter::matching::ReadFilter;
-use read_filter::output::{write_config_header, write_read_report_header, write_stats_header};
-use read_filter::stat::{QualStats, RunningStats};
+use read_filter::merge::{CountMatrix, CountsTable};
+use read_filter::multiqc;
+use read_filter::naming::{
+    sample_name_from_table, Compression, OutputKind, OutputTemplate, TableFormat,
+};
+use read_filter::output::{
+    table_metadata, write_config_header, write_counts_json, write_counts_table,
+    write_input_stats_header, write_stats_header, CountsFormat,
+};
+use read_filter::provenance::{InputSource, Provenance, Run};
+use read_filter::sink::{FastqSink, JsonlReadReportSink, MatchSink, ReadReportSink};
+use read_filter::stat::{QualBins, QualStats, ReadStats, RunningStats};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
 #[allow(unused_imports)]
 use std::todo;
 use std::{
-    fs::File,
-    io::{BufWriter, Write},
-    iter::Iterator,
+    io::{self, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
 };
 
-const OUT_ENDING: &str = ".processed.tsv";
-const RR_ENDING: &str = ".readreport.tsv";
-const QC_ENDING: &str = ".quality.tsv";
-fn main() {
-    let cfg = ProgConfig::from_cli().unwrap_graceful();
-    let infile = Path::new(&cfg.infile);
+/// Exits if features depending on the read qualities are requested for input without them
+fn refuse_quality_features(
+    filter: &FilterParams,
+    outputs: &[&str],
+    infile: &str,
+    format: ReadFormat,
+) {
+    let mut requested = Vec::new();
+    if filter.min_peak_qual.is_some() {
+        requested.push("qual_peak");
+    }
+    if filter.min_mean_qual.is_some() {
+        requested.push("qual_mean");
+    }
+    requested.extend(outputs);
+    if !requested.is_empty() {
+        eprintln!(
+            "{} input {} has no quality information, remove: {}",
+            format,
+            infile,
+            requested.join(", ")
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Opens and checks all inputs, before any output is created
+///
+/// `quality_outputs` names the requested outputs depending on the read qualities.
+/// The `InputSource`s track the inputs for the provenance while they are read.
+fn open_inputs(
+    infiles: &[String],
+    filter: &FilterParams,
+    quality_outputs: &[&str],
+) -> (Vec<(String, Records, ReadError)>, Vec<InputSource>) {
+    infiles
+        .iter()
+        .map(|infile| {
+            let input = open_reads_with_digest(infile)
+                .unwrap_formatful(&format!("Invalid input {}", infile));
+            if !input.has_quality {
+                refuse_quality_features(filter, quality_outputs, infile, input.format);
+            }
+            let source = InputSource::new(infile, &input);
+            ((infile.clone(), input.records, input.error), source)
+        })
+        .unzip()
+}
+
+/// Exits if any of the outputs exists unless overwriting is forced
+fn refuse_existing(paths: &[&PathBuf], force: bool) {
+    let existing: Vec<_> = paths
+        .iter()
+        .filter(|p| p.exists())
+        .map(|p| p.display().to_string())
+        .collect();
+    if !force && !existing.is_empty() {
+        eprintln!(
+            "Refusing to overwrite existing outputs, use --force: {}",
+            existing.join(", ")
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Plain or compressed buffered output
+///
+/// Compressed streams are completed by `finish`, unlike niffler's writers that finish them when dropped and
+/// swallow the errors.
+enum OutputWriter {
+    Plain(Box<dyn Write>),
+    Gzip(GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+}
+
+impl OutputWriter {
+    /// Writes the end of a compressed stream and flushes the output
+    fn finish(self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(mut out) => out.flush(),
+            OutputWriter::Gzip(encoder) => encoder.finish()?.flush(),
+            OutputWriter::Zstd(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            OutputWriter::Plain(out) => out.write(buf),
+            OutputWriter::Gzip(encoder) => encoder.write(buf),
+            OutputWriter::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            OutputWriter::Plain(out) => out.flush(),
+            OutputWriter::Gzip(encoder) => encoder.flush(),
+            OutputWriter::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Plain or compressed buffered writer, the path `-` writes to stdout
+fn create_file((path, compression): (PathBuf, Compression)) -> OutputWriter {
+    let out: Box<dyn Write> = if path == Path::new("-") {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        let file = std::fs::File::create(&path).unwrap_messageful(&format!(
+            "Could not create output file at: {:?}",
+            path.to_str().unwrap()
+        ));
+        Box::new(BufWriter::new(file))
+    };
+    let level = compression.level();
+    match compression {
+        Compression::None => OutputWriter::Plain(out),
+        Compression::Gzip => {
+            OutputWriter::Gzip(GzEncoder::new(out, flate2::Compression::new(level)))
+        }
+        Compression::Zstd => OutputWriter::Zstd(
+            zstd::Encoder::new(out, level as i32)
+                .unwrap_messageful("Could not start zstd compression"),
+        ),
+    }
+}
+
+/// Counts table or read report, the columnar formats need a writer that is `Send`
+enum TableFile {
+    Text(OutputWriter),
+    #[cfg(feature = "columnar")]
+    Columnar(Box<dyn Write + Send>),
+}
+
+/// Plain buffered writer for the columnar formats or as `create_file` for the others
+fn create_table(file: (PathBuf, Compression), format: TableFormat) -> TableFile {
+    #[cfg(feature = "columnar")]
+    if format.is_columnar() {
+        let (path, _) = file;
+        if path == Path::new("-") {
+            return TableFile::Columnar(Box::new(BufWriter::new(io::stdout())));
+        }
+        let out = std::fs::File::create(&path).unwrap_messageful(&format!(
+            "Could not create output file at: {:?}",
+            path.to_str().unwrap()
+        ));
+        return TableFile::Columnar(Box::new(BufWriter::new(out)));
+    }
+    let _ = format;
+    TableFile::Text(create_file(file))
+}
+
+/// Read report in one of the `TableFormat`s
+enum ReadReport {
+    Tsv(Box<ReadReportSink<OutputWriter>>),
+    Jsonl(Box<JsonlReadReportSink<OutputWriter>>),
+    #[cfg(feature = "columnar")]
+    Columnar(Box<ColumnarReadReportSink<Box<dyn Write + Send>>>),
+}
+
+impl ReadReport {
+    fn new(file: TableFile, format: TableFormat, seq_len: usize) -> io::Result<Self> {
+        match file {
+            TableFile::Text(buf) if format == TableFormat::Json => {
+                Ok(ReadReport::Jsonl(Box::new(JsonlReadReportSink::new(buf))))
+            }
+            TableFile::Text(buf) => Ok(ReadReport::Tsv(Box::new(ReadReportSink::new(
+                buf, seq_len,
+            )?))),
+            #[cfg(feature = "columnar")]
+            TableFile::Columnar(buf) => ColumnarReadReportSink::new(buf, format, seq_len)
+                .map(|sink| ReadReport::Columnar(Box::new(sink))),
+        }
+    }
+
+    /// Only the columnar formats store metadata
+    fn set_metadata(&mut self, metadata: Map<String, Value>) {
+        #[cfg(feature = "columnar")]
+        if let ReadReport::Columnar(sink) = self {
+            sink.set_metadata(metadata);
+        }
+        let _ = metadata;
+    }
+
+    /// Finishes the sink and the compression of the text formats
+    ///
+    /// The rows are written while the inputs are read, so the text formats end with the `provenance`
+    /// as `#` lines or a last JSON object, the columnar ones store it with the metadata.
+    fn close(mut self, provenance: &Provenance) -> io::Result<()> {
+        self.finish()?;
+        match self {
+            ReadReport::Tsv(sink) => {
+                let mut buf = sink.into_inner()?;
+                provenance.write_header(&mut buf)?;
+                buf.finish()
+            }
+            ReadReport::Jsonl(sink) => {
+                let mut buf = sink.into_inner();
+                serde_json::to_writer(&mut buf, &json!({ "provenance": provenance }))?;
+                writeln!(buf)?;
+                buf.finish()
+            }
+            #[cfg(feature = "columnar")]
+            ReadReport::Columnar(_) => Ok(()),
+        }
+    }
+}
+
+impl MatchSink for ReadReport {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        match self {
+            ReadReport::Tsv(sink) => sink.consume(m),
+            ReadReport::Jsonl(sink) => sink.consume(m),
+            #[cfg(feature = "columnar")]
+            ReadReport::Columnar(sink) => sink.consume(m),
+        }
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            ReadReport::Tsv(sink) => sink.finish(),
+            ReadReport::Jsonl(sink) => sink.finish(),
+            #[cfg(feature = "columnar")]
+            ReadReport::Columnar(sink) => sink.finish(),
+        }
+    }
+}
+
+/// Streams the inputs in order into the same sinks, the optional counter and the optional whole-read QC
+///
+/// The sinks are not finished. Returns the total and the per input `RunningStats`.
+fn filter_inputs(
+    inputs: Vec<(String, Records, ReadError)>,
+    filter: &FilterParams,
+    sinks: &mut Vec<&mut dyn MatchSink>,
+    mut counter: Option<&mut Counter<Vec<u8>>>,
+    mut read_stats: Option<&mut ReadStats>,
+) -> (RunningStats, Vec<(String, RunningStats)>) {
+    let mut stats = RunningStats::default();
+    let mut input_stats = Vec::new();
+    for (infile, records, error) in inputs {
+        let mut file_stats = RunningStats::default();
+        let mut rf = ReadFilter::new(records, filter, &mut file_stats);
+        if let Some(read_stats) = read_stats.as_deref_mut() {
+            rf = rf.with_read_stats(read_stats);
+        }
+        match counter.as_deref_mut() {
+            Some(counter) if sinks.is_empty() => rf.count_into(counter),
+            counter => {
+                let mut pipeline: Vec<&mut dyn MatchSink> = vec![&mut *sinks];
+                if let Some(counter) = counter {
+                    pipeline.push(counter);
+                }
+                rf.feed(&mut pipeline)
+                    .unwrap_messageful("Error while writing output");
+            }
+        }
+        error
+            .check()
+            .unwrap_formatful(&format!("Invalid input {}", infile));
+        stats += &file_stats;
+        input_stats.push((infile, file_stats));
+    }
+    (stats, input_stats)
+}
+
+/// Config and stats headers
+fn write_summary<W: Write>(
+    buf: &mut W,
+    filter: &FilterParams,
+    stats: &RunningStats,
+    input_stats: &[(String, RunningStats)],
+) -> io::Result<()> {
+    write_config_header(buf, filter)?;
+    write_input_stats_header(buf, input_stats)?;
+    write_stats_header(buf, stats)
+}
+
+/// Diversity metrics of the counts, including the coverage of a configured design
+fn diversity(counter: &Counter<Vec<u8>>, filter: &FilterParams) -> Diversity {
+    let design = filter.design.as_ref().map(|template| {
+        DesignTemplate::new(template, filter.insert_length as usize).unwrap_graceful()
+    });
+    Diversity::new(counter, design.as_ref())
+}
+
+/// Pretty printed JSON, e.g. of the diversity metrics
+fn write_json<T: Serialize>(file: (PathBuf, Compression), value: &T) {
+    let mut out = create_file(file);
+    serde_json::to_writer_pretty(&mut out, value)
+        .map_err(io::Error::from)
+        .and_then(|_| writeln!(out))
+        .and_then(|_| out.finish())
+        .unwrap_messageful("Error while writing output");
+}
+
+/// JSON object of `value` with the `provenance` of the run added
+fn write_json_with_provenance<T: Serialize>(
+    file: (PathBuf, Compression),
+    value: &T,
+    provenance: &Provenance,
+) {
+    let mut value = json!(value);
+    value["provenance"] = json!(provenance);
+    write_json(file, &value);
+}
+
+/// Counts table preceded by the provenance, config, stats and diversity headers or with them as metadata
+#[allow(clippy::too_many_arguments)]
+fn write_counts(
+    file: TableFile,
+    table_format: TableFormat,
+    provenance: &Provenance,
+    filter: &FilterParams,
+    stats: &RunningStats,
+    input_stats: &[(String, RunningStats)],
+    counter: &Counter<Vec<u8>>,
+    diversity: &Diversity,
+    format: &CountsFormat,
+) -> io::Result<()> {
+    let metadata = || {
+        let mut metadata = table_metadata(filter, stats, input_stats);
+        let counted_reads: usize = counter.values().sum();
+        metadata.insert("counted_reads".into(), json!(counted_reads));
+        metadata.insert("diversity".into(), json!(diversity));
+        metadata.insert("provenance".into(), json!(provenance));
+        metadata
+    };
+    match file {
+        TableFile::Text(mut buf) if table_format == TableFormat::Json => {
+            write_counts_json(&mut buf, counter, format, metadata())?;
+            buf.finish()
+        }
+        TableFile::Text(mut buf) => {
+            provenance.write_header(&mut buf)?;
+            write_summary(&mut buf, filter, stats, input_stats)?;
+            diversity.write_header(&mut buf)?;
+            write_counts_table(&mut buf, counter, format)?;
+            buf.finish()
+        }
+        #[cfg(feature = "columnar")]
+        TableFile::Columnar(buf) => {
+            columnar::write_counts(buf, table_format, counter, format, &metadata())
+        }
+    }
+}
+
+/// QC report with one row per start position and quality or grouped into `bins`
+fn write_qual_report<W: Write>(
+    buf: &mut W,
+    provenance: &Provenance,
+    qual_stats: &QualStats,
+    filter: &FilterParams,
+    bins: Option<&QualBins>,
+) -> io::Result<()> {
+    let seq_len = filter.insert_length as usize;
+    provenance.write_header(buf)?;
+    match bins {
+        Some(bins) => qual_stats.write_binned_to_buf(buf, seq_len, bins),
+        None => qual_stats.write_to_buf(buf, seq_len),
+    }
+}
+
+/// Quality distribution per insert position preceded by the provenance headers
+fn write_position_quality(
+    file: (PathBuf, Compression),
+    provenance: &Provenance,
+    qual_stats: &QualStats,
+) {
+    let mut out = create_file(file);
+    provenance
+        .write_header(&mut out)
+        .and_then(|_| qual_stats.write_position_table(&mut out))
+        .and_then(|_| out.finish())
+        .unwrap_messageful("Error while writing output");
+}
+
+/// Whole-read QC preceded by the provenance headers
+fn write_read_qc(
+    file: (PathBuf, Compression),
+    provenance: &Provenance,
+    read_stats: &ReadStats,
+    filter: &FilterParams,
+) {
+    let mut out = create_file(file);
+    provenance
+        .write_header(&mut out)
+        .and_then(|_| read_stats.write_to_buf(&mut out, filter.window()))
+        .and_then(|_| out.finish())
+        .unwrap_messageful("Error while writing output");
+}
+
+fn run_legacy(cfg: ProgConfig, run: &Run) {
     let outdir = Path::new(&cfg.outdir);
     std::fs::create_dir_all(outdir).unwrap_messageful(&format!(
         "Could not create output directory at: {:?}",
         outdir.to_str().unwrap()
     ));
-    let bname = infile
-        .file_name()
-        .unwrap_graceful("Input needs to be a file"); // TODO file name is just basename only errs when given ..
-    let get_outpath = |ending: &str| -> PathBuf {
-        let oname = String::from(bname.to_str().unwrap())
-            .replace(".fastq.gz", ending)
-            .replace(".txt.gz", ending)
-            .replace(".fastq", ending);
-        outdir.join(oname)
+    let get_outpath = |kind: OutputKind| -> (PathBuf, Compression) {
+        cfg.output_template
+            .compressed_path(outdir, &cfg.sample_name, kind, &cfg.compression)
     };
-    fn create_file(path: PathBuf) -> File {
-        std::fs::File::create(&path).unwrap_messageful(&format!(
-            "Could not create output file at: {:?}",
-            path.to_str().unwrap()
-        ))
+    let get_table_path = |kind: OutputKind| -> (PathBuf, Compression) {
+        cfg.output_template.table_path(
+            outdir,
+            &cfg.sample_name,
+            kind,
+            cfg.table_format,
+            &cfg.compression,
+        )
+    };
+    let outfile = get_table_path(OutputKind::Processed);
+    let rr_file = get_table_path(OutputKind::ReadReport);
+    let qc_file = get_outpath(OutputKind::Quality);
+    let pos_qual_file = get_outpath(OutputKind::PositionQuality);
+    let fq_file = get_outpath(OutputKind::Matches);
+    let div_file = get_outpath(OutputKind::Diversity);
+    let read_qc_file = get_outpath(OutputKind::ReadQc);
+    let mqc_files = [
+        OutputKind::MultiqcFunnel,
+        OutputKind::MultiqcQuality,
+        OutputKind::MultiqcDiversity,
+    ]
+    .map(get_outpath);
+    let html_file = get_outpath(OutputKind::Html);
+    let provenance_file = get_outpath(OutputKind::Provenance);
+    let mut requested = vec![&outfile.0];
+    let mut quality_outputs = Vec::new();
+    if cfg.rr_required {
+        requested.push(&rr_file.0);
+        quality_outputs.push("--read-report");
+    }
+    if cfg.qc_required {
+        requested.extend([&qc_file.0, &pos_qual_file.0]);
+        quality_outputs.push("--qc-report");
     }
-    fn create_buffered_file(path: PathBuf) -> BufWriter<File> {
-        BufWriter::new(create_file(path))
+    if cfg.fastq_required {
+        requested.push(&fq_file.0);
+        quality_outputs.push("--fastq-export");
+    }
+    if cfg.diversity_required {
+        requested.push(&div_file.0);
+    }
+    if cfg.read_qc_required {
+        requested.push(&read_qc_file.0);
+        quality_outputs.push("--read-qc");
+    }
+    let [funnel_file, quality_file, mqc_div_file] = mqc_files;
+    if cfg.multiqc_required {
+        requested.extend([&funnel_file.0, &mqc_div_file.0]);
+    }
+    if cfg.html_required {
+        requested.push(&html_file.0);
+        quality_outputs.push("--html-report");
     }
-    let outfile = get_outpath(OUT_ENDING);
-    let rr_file = get_outpath(RR_ENDING);
-    let qc_file = get_outpath(QC_ENDING);
-    let mut ofile = create_buffered_file(outfile);
 
-    // FASTQ parsing
-    let (reader, _compression) = niffler::from_path(infile).unwrap_formatful("Invalid input path!");
-    let fq_reader = fastq::Reader::new(reader);
+    let (inputs, sources) = open_inputs(&cfg.infiles, &cfg.filter, &quality_outputs);
+    // Inputs without qualities get the MultiQC content without the quality profile
+    let quality_profile = cfg.multiqc_required && sources.iter().all(InputSource::has_quality);
+    if quality_profile {
+        requested.push(&quality_file.0);
+    }
+    let outputs: Vec<String> = requested
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    if cfg.provenance_required {
+        requested.push(&provenance_file.0);
+    }
+    refuse_existing(&requested, cfg.force);
+    let ofile = create_table(outfile, cfg.table_format);
 
-    let mut stats = RunningStats::default();
     let mut qual_stats = QualStats::new();
-    let rf = ReadFilter::new(fq_reader.records(), &cfg, &mut stats);
-
-    let counter = match (cfg.rr_required, cfg.qc_required) {
-        (false, false) => rf.map(|a| a.seq).collect::<Counter<Vec<u8>>>(),
-        (true, false) => {
-            let mut rr_file = create_file(rr_file);
-            write_read_report_header(&mut rr_file, cfg.insert_length as usize)
-                .unwrap_messageful("Error while writing output");
-            let mut wtr = WriterBuilder::new()
-                .has_headers(false)
-                .delimiter(b'\t')
-                .from_writer(rr_file);
-            rf.map(|a| {
-                a.write_read_report_line(&mut wtr).unwrap();
-                a.seq
-            })
-            .collect::<Counter<Vec<u8>>>()
-        }
-        (false, true) => rf
-            .map(|a| {
-                qual_stats.append(&a);
-                a.seq
-            })
-            .collect::<Counter<Vec<u8>>>(),
-        (true, true) => {
-            let mut rr_file = create_file(rr_file);
-            write_read_report_header(&mut rr_file, cfg.insert_length as usize)
-                .unwrap_messageful("Error while writing output");
-            let mut wtr = WriterBuilder::new()
-                .has_headers(false)
-                .delimiter(b'\t')
-                .from_writer(rr_file);
-            rf.map(|a| {
-                qual_stats.append(&a);
-                a.write_read_report_line(&mut wtr).unwrap();
-                a.seq
-            })
-            .collect::<Counter<Vec<u8>>>()
-        }
+    let mut read_stats = ReadStats::new();
+    let mut counter = Counter::new();
+    let mut read_report = if cfg.rr_required {
+        let sink = ReadReport::new(
+            create_table(rr_file, cfg.table_format),
+            cfg.table_format,
+            cfg.filter.insert_length as usize,
+        )
+        .unwrap_messageful("Error while writing output");
+        Some(sink)
+    } else {
+        None
     };
+    let mut fastq_export = if cfg.fastq_required {
+        Some(FastqSink::new(create_file(fq_file)))
+    } else {
+        None
+    };
+
+    // Sinks besides the counter are composed from the CLI flags
+    let mut sinks: Vec<&mut dyn MatchSink> = Vec::new();
+    if let Some(sink) = read_report.as_mut() {
+        sinks.push(sink);
+    }
+    if cfg.qc_required || quality_profile || cfg.html_required {
+        sinks.push(&mut qual_stats);
+    }
+    if let Some(sink) = fastq_export.as_mut() {
+        sinks.push(sink);
+    }
+    let (stats, input_stats) = filter_inputs(
+        inputs,
+        &cfg.filter,
+        &mut sinks,
+        Some(&mut counter),
+        Some(&mut read_stats).filter(|_| cfg.read_qc_required),
+    );
+    let mut provenance = run.finish(&sources, Some(&cfg.filter));
+    // The read report may store the final stats, so it is finished after the other sinks
+    if let Some(mut sink) = fastq_export {
+        sink.finish()
+            .and_then(|_| sink.into_inner()?.finish())
+            .unwrap_messageful("Error while writing output");
+    }
+    if let Some(mut sink) = read_report {
+        let mut metadata = table_metadata(&cfg.filter, &stats, &input_stats);
+        metadata.insert("provenance".into(), json!(provenance));
+        sink.set_metadata(metadata);
+        sink.close(&provenance)
+            .unwrap_messageful("Error while writing output");
+    }
 
-    write_config_header(&mut ofile, &cfg).unwrap_messageful("Error while writing output");
-    write_stats_header(&mut ofile, &stats).unwrap();
-    writeln!(ofile, "seq\treads").unwrap();
-    for (seq, count) in counter.iter() {
-        writeln!(ofile, "{}\t{}", std::str::from_utf8(seq).unwrap(), count).unwrap();
+    let diversity = diversity(&counter, &cfg.filter);
+    write_counts(
+        ofile,
+        cfg.table_format,
+        &provenance,
+        &cfg.filter,
+        &stats,
+        &input_stats,
+        &counter,
+        &diversity,
+        &cfg.counts_format,
+    )
+    .unwrap_messageful("Error while writing output");
+    if cfg.diversity_required {
+        write_json_with_provenance(div_file, &diversity, &provenance);
     }
 
     if cfg.qc_required {
-        let mut qc_file = create_buffered_file(qc_file);
-        qual_stats
-            .write_to_buf(&mut qc_file, cfg.insert_length as usize)
+        let mut qc_file = create_file(qc_file);
+        write_qual_report(
+            &mut qc_file,
+            &provenance,
+            &qual_stats,
+            &cfg.filter,
+            cfg.qual_bins.as_ref(),
+        )
+        .and_then(|_| qc_file.finish())
+        .unwrap_messageful("Error while writing output");
+        write_position_quality(pos_qual_file, &provenance, &qual_stats);
+    }
+    if cfg.read_qc_required {
+        write_read_qc(read_qc_file, &provenance, &read_stats, &cfg.filter);
+    }
+    if cfg.multiqc_required {
+        write_json_with_provenance(
+            funnel_file,
+            &multiqc::funnel(&cfg.sample_name, &stats),
+            &provenance,
+        );
+        write_json_with_provenance(
+            mqc_div_file,
+            &multiqc::diversity_table(&cfg.sample_name, &diversity),
+            &provenance,
+        );
+    }
+    if quality_profile {
+        write_json_with_provenance(
+            quality_file,
+            &multiqc::quality_profile(&cfg.sample_name, &qual_stats),
+            &provenance,
+        );
+    }
+    if cfg.html_required {
+        let report = HtmlReport {
+            sample: &cfg.sample_name,
+            filter: &cfg.filter,
+            stats: &stats,
+            qual_stats: &qual_stats,
+            counter: &counter,
+            provenance: &provenance,
+        };
+        let mut html_file = create_file(html_file);
+        report
+            .write_to_buf(&mut html_file)
+            .and_then(|_| html_file.finish())
             .unwrap_messageful("Error while writing output");
     }
+    if cfg.provenance_required {
+        provenance.outputs = outputs;
+        write_json(provenance_file, &provenance);
+    }
+}
+
+/// Output of a subcommand, checked against overwriting before the inputs are opened
+fn stream_output(output: &OutputFile) -> (PathBuf, Compression) {
+    let path = PathBuf::from(&output.path);
+    if output.path != "-" {
+        refuse_existing(&[&path], output.force);
+    }
+    (path, output.compression)
+}
+
+/// Additional output file of a subcommand, compressed as inferred from its name
+fn extra_output(path: &str, force: bool) -> (PathBuf, Compression) {
+    let path = PathBuf::from(path);
+    refuse_existing(&[&path], force);
+    let compression = Compression::from_path(&path);
+    (path, compression)
+}
+
+/// MultiQC custom content files of a subcommand, named by the default output template in a created directory
+fn multiqc_outputs(
+    multiqc: &MultiqcOutput,
+    kinds: [OutputKind; 2],
+    force: bool,
+) -> [(PathBuf, Compression); 2] {
+    std::fs::create_dir_all(&multiqc.outdir).unwrap_messageful(&format!(
+        "Could not create output directory at: {:?}",
+        multiqc.outdir
+    ));
+    let template = OutputTemplate::default();
+    kinds.map(|kind| {
+        let path = template.path(&multiqc.outdir, &multiqc.sample_name, kind);
+        refuse_existing(&[&path], force);
+        (path, Compression::None)
+    })
+}
+
+/// Provenance sidecar of a subcommand listing its outputs, `-` for stdout
+fn write_provenance_file(
+    file: Option<(PathBuf, Compression)>,
+    mut provenance: Provenance,
+    outputs: &[Option<&String>],
+) {
+    if let Some(file) = file {
+        provenance.outputs = outputs.iter().flatten().map(|p| p.to_string()).collect();
+        write_json(file, &provenance);
+    }
+}
+
+fn run_count(cfg: StreamConfig, run: &Run) {
+    let output = stream_output(&cfg.output);
+    let div_file = cfg
+        .diversity_json
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let mqc_files = cfg.multiqc.as_ref().map(|multiqc| {
+        let kinds = [OutputKind::MultiqcFunnel, OutputKind::MultiqcDiversity];
+        multiqc_outputs(multiqc, kinds, cfg.output.force)
+    });
+    let mqc_names: Vec<String> = mqc_files
+        .iter()
+        .flatten()
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    let provenance_file = cfg
+        .provenance
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let (inputs, sources) = open_inputs(&cfg.infiles, &cfg.filter, &[]);
+    let out = create_table(output, cfg.table_format);
+    let mut counter = Counter::new();
+    let (stats, input_stats) = filter_inputs(
+        inputs,
+        &cfg.filter,
+        &mut Vec::new(),
+        Some(&mut counter),
+        None,
+    );
+    let provenance = run.finish(&sources, Some(&cfg.filter));
+    let diversity = diversity(&counter, &cfg.filter);
+    write_counts(
+        out,
+        cfg.table_format,
+        &provenance,
+        &cfg.filter,
+        &stats,
+        &input_stats,
+        &counter,
+        &diversity,
+        &cfg.counts_format,
+    )
+    .unwrap_messageful("Error while writing output");
+    if let Some(div_file) = div_file {
+        write_json_with_provenance(div_file, &diversity, &provenance);
+    }
+    let mut outputs = vec![Some(&cfg.output.path), cfg.diversity_json.as_ref()];
+    outputs.extend(mqc_names.iter().map(Some));
+    if let (Some(multiqc), Some([funnel_file, div_file])) = (&cfg.multiqc, mqc_files) {
+        write_json_with_provenance(
+            funnel_file,
+            &multiqc::funnel(&multiqc.sample_name, &stats),
+            &provenance,
+        );
+        write_json_with_provenance(
+            div_file,
+            &multiqc::diversity_table(&multiqc.sample_name, &diversity),
+            &provenance,
+        );
+    }
+    write_provenance_file(provenance_file, provenance, &outputs);
+}
+
+/// The stats of `extract` and `qc` are reported on stderr
+fn run_extract(cfg: StreamConfig, run: &Run) {
+    let output = stream_output(&cfg.output);
+    let provenance_file = cfg
+        .provenance
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let (inputs, sources) = open_inputs(&cfg.infiles, &cfg.filter, &["the extract subcommand"]);
+    let mut sink = FastqSink::new(create_file(output));
+    let (stats, input_stats) = filter_inputs(inputs, &cfg.filter, &mut vec![&mut sink], None, None);
+    sink.finish()
+        .and_then(|_| sink.into_inner()?.finish())
+        .unwrap_messageful("Error while writing output");
+    write_summary(&mut io::stderr(), &cfg.filter, &stats, &input_stats).unwrap();
+    let provenance = run.finish(&sources, Some(&cfg.filter));
+    write_provenance_file(provenance_file, provenance, &[Some(&cfg.output.path)]);
+}
+
+fn run_qc(cfg: StreamConfig, run: &Run) {
+    let output = stream_output(&cfg.output);
+    let read_qc_file = cfg
+        .read_qc
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let pos_qual_file = cfg
+        .position_quality
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let mqc_files = cfg.multiqc.as_ref().map(|multiqc| {
+        let kinds = [OutputKind::MultiqcFunnel, OutputKind::MultiqcQuality];
+        multiqc_outputs(multiqc, kinds, cfg.output.force)
+    });
+    let mqc_names: Vec<String> = mqc_files
+        .iter()
+        .flatten()
+        .map(|(path, _)| path.display().to_string())
+        .collect();
+    let provenance_file = cfg
+        .provenance
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let (inputs, sources) = open_inputs(&cfg.infiles, &cfg.filter, &["the qc subcommand"]);
+    let mut out = create_file(output);
+    let mut qual_stats = QualStats::new();
+    let mut read_stats = ReadStats::new();
+    let (stats, input_stats) = filter_inputs(
+        inputs,
+        &cfg.filter,
+        &mut vec![&mut qual_stats],
+        None,
+        Some(&mut read_stats).filter(|_| read_qc_file.is_some()),
+    );
+    let provenance = run.finish(&sources, Some(&cfg.filter));
+    write_qual_report(
+        &mut out,
+        &provenance,
+        &qual_stats,
+        &cfg.filter,
+        cfg.qual_bins.as_ref(),
+    )
+    .and_then(|_| out.finish())
+    .unwrap_messageful("Error while writing output");
+    if let Some(pos_qual_file) = pos_qual_file {
+        write_position_quality(pos_qual_file, &provenance, &qual_stats);
+    }
+    if let Some(read_qc_file) = read_qc_file {
+        write_read_qc(read_qc_file, &provenance, &read_stats, &cfg.filter);
+    }
+    let mut outputs = vec![
+        Some(&cfg.output.path),
+        cfg.position_quality.as_ref(),
+        cfg.read_qc.as_ref(),
+    ];
+    outputs.extend(mqc_names.iter().map(Some));
+    if let (Some(multiqc), Some([funnel_file, quality_file])) = (&cfg.multiqc, mqc_files) {
+        write_json_with_provenance(
+            funnel_file,
+            &multiqc::funnel(&multiqc.sample_name, &stats),
+            &provenance,
+        );
+        write_json_with_provenance(
+            quality_file,
+            &multiqc::quality_profile(&multiqc.sample_name, &qual_stats),
+            &provenance,
+        );
+    }
+    write_summary(&mut io::stderr(), &cfg.filter, &stats, &input_stats).unwrap();
+    write_provenance_file(provenance_file, provenance, &outputs);
+}
+
+/// Reads the counts tables named by their file names
+fn read_tables(paths: &[String]) -> (Vec<(String, CountsTable)>, Vec<InputSource>) {
+    let mut samples: Vec<(String, CountsTable)> = Vec::new();
+    let mut sources = Vec::new();
+    for path in paths {
+        let name = sample_name_from_table(path)
+            .unwrap_graceful(&format!("Cannot derive a sample name from {}", path));
+        if samples.iter().any(|(other, _)| *other == name) {
+            eprintln!("Duplicate sample name {} of {}", name, path);
+            std::process::exit(1);
+        }
+        let input =
+            open_with_digest(path).unwrap_formatful(&format!("Invalid counts table {}", path));
+        sources.push(InputSource::counts_table(path, &input));
+        let table = CountsTable::from_reader(BufReader::new(input.reader))
+            .unwrap_formatful(&format!("Invalid counts table {}", path));
+        samples.push((name, table));
+    }
+    (samples, sources)
+}
+
+/// The provenance lists the counts tables as inputs and has no config
+fn run_merge(cfg: MergeConfig, run: &Run) {
+    let output = stream_output(&cfg.output);
+    let provenance_file = cfg
+        .provenance
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let (tables, sources) = read_tables(&cfg.tables);
+    let matrix = CountMatrix::merge(tables, cfg.lenient).unwrap_graceful();
+    let provenance = run.finish(&sources, None);
+    let mut out = create_file(output);
+    provenance
+        .write_header(&mut out)
+        .and_then(|_| {
+            if cfg.long {
+                matrix.write_long(&mut out)
+            } else {
+                matrix.write_wide(&mut out)
+            }
+        })
+        .and_then(|_| out.finish())
+        .unwrap_messageful("Error while writing output");
+    write_provenance_file(provenance_file, provenance, &[Some(&cfg.output.path)]);
+}
+
+fn run_enrich(cfg: EnrichConfig, run: &Run) {
+    let output = stream_output(&cfg.output);
+    let provenance_file = cfg
+        .provenance
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let (tables, sources) = read_tables(&cfg.tables);
+    let matrix = CountMatrix::merge(tables, cfg.lenient).unwrap_graceful();
+    let enrichment = Enrichment::new(&matrix, &cfg.params).unwrap_graceful();
+    let provenance = run.finish(&sources, None);
+    let mut out = create_file(output);
+    provenance
+        .write_header(&mut out)
+        .and_then(|_| enrichment.write(&mut out))
+        .and_then(|_| out.finish())
+        .unwrap_messageful("Error while writing output");
+    write_provenance_file(provenance_file, provenance, &[Some(&cfg.output.path)]);
+}
+
+/// Scans the first reads and suggests a window covering the requested fraction of flank hits
+///
+/// The inputs are usually not read to their end, so their provenance lacks the checksum.
+fn run_calibrate(cfg: CalibrateConfig, run: &Run) {
+    let output = stream_output(&cfg.output);
+    let config_file = cfg
+        .write_config
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let provenance_file = cfg
+        .provenance
+        .as_ref()
+        .map(|path| extra_output(path, cfg.output.force));
+    let (inputs, sources) = open_inputs(&cfg.infiles, &cfg.filter, &[]);
+    let mut hist = PositionHistogram::new(&cfg.filter);
+    let mut remaining = cfg.reads;
+    for (infile, records, error) in inputs {
+        for rec in records.take(remaining) {
+            hist.append(&rec);
+            remaining -= 1;
+        }
+        error
+            .check()
+            .unwrap_formatful(&format!("Invalid input {}", infile));
+    }
+    // Suggested before any output is created, so a failure leaves no files behind
+    let config_text = config_file.as_ref().map(|(path, _)| {
+        let position = hist
+            .suggest(cfg.coverage)
+            .unwrap_graceful("No read contains the flanks, cannot suggest a window");
+        let calibrated = FilterParams {
+            position: Some(position),
+            ..cfg.filter.clone()
+        };
+        ConfigFormat::from_path(path)
+            .to_string(&FilterConf::from(&calibrated))
+            .unwrap_graceful()
+    });
+    let provenance = run.finish(&sources, Some(&cfg.filter));
+    let mut out = create_file(output);
+    hist.write_to_buf(&mut out, cfg.coverage)
+        .and_then(|_| out.finish())
+        .unwrap_messageful("Error while writing output");
+    if let (Some(config_file), Some(text)) = (config_file, config_text) {
+        let mut out = create_file(config_file);
+        out.write_all(text.as_bytes())
+            .and_then(|_| out.finish())
+            .unwrap_messageful("Error while writing output");
+    }
+    write_provenance_file(
+        provenance_file,
+        provenance,
+        &[Some(&cfg.output.path), cfg.write_config.as_ref()],
+    );
+}
+
+fn main() {
+    let run = Run::start();
+    match Command::from_cli().unwrap_graceful() {
+        Command::Legacy(cfg) => run_legacy(cfg, &run),
+        Command::Count(cfg) => run_count(cfg, &run),
+        Command::Extract(cfg) => run_extract(cfg, &run),
+        Command::Qc(cfg) => run_qc(cfg, &run),
+        Command::Merge(cfg) => run_merge(cfg, &run),
+        Command::Enrich(cfg) => run_enrich(cfg, &run),
+        Command::Calibrate(cfg) => run_calibrate(cfg, &run),
+    }
 }
@@ -3,7 +3,7 @@ use bio::alphabets::dna;
 use bio::io::fastq;
 use csv::Writer;
 use std::fmt::Display;
-use std::{fmt, fs::File};
+use std::{fmt, io};
 
 /// Owned version of a succesful match
 #[derive(Debug, PartialEq)]
@@ -14,7 +14,8 @@ pub struct SearchMatch {
     ///
     /// ATTENTION:
     /// Direct encoding from fastq. For PHRED scores subtract 33!
-    pub quality: Vec<u8>, // TODO maybe have a variant that doesn't keep quality if not needed
+    /// If only the counts are needed use `ReadFilter::count_into` which skips this allocation
+    pub quality: Vec<u8>,
     /// Whether match occurred on the reverse complement strand
     pub reverse_strand: bool,
     /// Index from the (reverse complement) start starting the content sequence
@@ -66,7 +67,7 @@ impl SearchMatch {
     }
 
     /// Output a single tab-separated record for diagnostics
-    pub fn write_read_report_line(&self, wtr: &mut Writer<File>) -> csv::Result<()> {
+    pub fn write_read_report_line<W: io::Write>(&self, wtr: &mut Writer<W>) -> csv::Result<()> {
         let qual: Vec<_> = self.quality.iter().map(|v| v - 33).collect();
         wtr.serialize((
             std::str::from_utf8(&self.seq).unwrap(),
@@ -114,7 +115,7 @@ impl<'a> CandidateMatch<'a> {
     }
 
     pub fn materialize(self) -> SearchMatch {
-        //! Consumes self to produce an owned `SearchMatch`
+        //! Consumes self to produce an owned `SearchMatch` without an `id`
         //! calls `dna::revcomp` to produce the useful sequence orientation
         if !self.reverse_strand {
             SearchMatch {
@@ -155,4 +156,24 @@ impl<'a> CandidateMatch<'a> {
     pub fn seq(&self) -> &'a [u8] {
         self.seq
     }
+
+    /// Start of the insert, counted from the read end for reverse strand matches
+    pub fn start_pos(&self) -> u32 {
+        self.start_pos
+    }
+
+    pub fn oriented_seq<'b>(&self, buf: &'b mut Vec<u8>) -> &'b [u8]
+    where
+        'a: 'b,
+    {
+        //! Sequence in the orientation defined by the pattern without a fresh allocation
+        //! Reverse strand matches are complemented into `buf`, which is cleared beforehand
+        if !self.reverse_strand {
+            self.seq
+        } else {
+            buf.clear();
+            buf.extend(self.seq.iter().rev().map(|&c| dna::complement(c)));
+            buf
+        }
+    }
 }
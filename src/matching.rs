@@ -1,10 +1,12 @@
-use crate::config::ProgConfig;
+use crate::config::FilterParams;
 use crate::match_type::{CandidateMatch, SearchMatch};
-use crate::stat::RunningStats;
+use crate::sink::MatchSink;
+use crate::stat::count_seq;
+use crate::stat::{ReadStats, RunningStats};
 use crate::ExactPattern;
 use bio::alphabets::dna;
 use bio::io::fastq;
-use fastq::Records;
+use counter::Counter;
 use std::io;
 
 pub fn is_dna_char(char: &u8) -> bool {
@@ -28,7 +30,8 @@ pub struct PrecomputedPatterns {
 }
 
 impl PrecomputedPatterns {
-    pub fn new(cfg: &ProgConfig, expt_begin: usize, expt_end: usize) -> Self {
+    pub fn new(cfg: &FilterParams) -> Self {
+        let (expt_begin, expt_end) = cfg.window().unwrap_or((0, usize::MAX));
         let start_len = cfg.left_flank.len();
         let end_len = cfg.right_flank.len();
         let content_len = cfg.insert_length as usize;
@@ -58,73 +61,131 @@ impl PrecomputedPatterns {
     }
 }
 
-pub struct ReadFilter<'a, T: io::Read> {
-    fq_records: Records<T>,
+/// Items a `ReadFilter` can consume
+///
+/// Implemented for plain `fastq::Record`s and the results yielded by `fastq::Records`.
+pub trait IntoRecord {
+    /// `None` ends the filtering
+    fn into_record(self) -> Option<fastq::Record>;
+}
+
+impl IntoRecord for fastq::Record {
+    fn into_record(self) -> Option<fastq::Record> {
+        Some(self)
+    }
+}
+
+impl<E> IntoRecord for Result<fastq::Record, E> {
+    fn into_record(self) -> Option<fastq::Record> {
+        self.ok() // ParseErrors are silenced!
+    }
+}
+
+pub struct ReadFilter<'a, I> {
+    records: I,
     pats: PrecomputedPatterns,
     min_mean_qual: Option<u8>,
     min_peak_qual: Option<u8>,
     stats: &'a mut RunningStats,
+    read_stats: Option<&'a mut ReadStats>,
 }
 
-impl<'a, T> ReadFilter<'a, T>
+impl<'a, I> ReadFilter<'a, I>
 where
-    T: io::Read,
+    I: Iterator,
+    I::Item: IntoRecord,
 {
-    pub fn new(fq_records: Records<T>, cfg: &ProgConfig, stats: &'a mut RunningStats) -> Self {
-        let expt_begin = cfg.expected_start.saturating_sub(cfg.position_tolerance) as usize;
-        let expt_end = (cfg.expected_start + cfg.position_tolerance) as usize;
-
-        let min_mean_qual = cfg.min_mean_qual;
-        let min_peak_qual = cfg.min_peak_qual;
-        let pats = PrecomputedPatterns::new(cfg, expt_begin, expt_end);
-
+    /// Filter any iterator of records, e.g. `fastq::Reader::records()`
+    pub fn new<R>(records: R, cfg: &FilterParams, stats: &'a mut RunningStats) -> Self
+    where
+        R: IntoIterator<IntoIter = I>,
+    {
         ReadFilter {
-            fq_records,
-            pats,
-            min_mean_qual,
-            min_peak_qual,
+            records: records.into_iter(),
+            pats: PrecomputedPatterns::new(cfg),
+            min_mean_qual: cfg.min_mean_qual,
+            min_peak_qual: cfg.min_peak_qual,
             stats,
+            read_stats: None,
+        }
+    }
+
+    /// Also collects the whole-read QC of every record in the same pass
+    pub fn with_read_stats(mut self, read_stats: &'a mut ReadStats) -> Self {
+        self.read_stats = Some(read_stats);
+        self
+    }
+
+    /// Applies the positional and quality criteria to a single record and updates the `RunningStats`
+    fn filter_record<'r>(&mut self, rec: &'r fastq::Record) -> Option<CandidateMatch<'r>> {
+        self.stats.total_reads += 1;
+        if let Some(read_stats) = self.read_stats.as_deref_mut() {
+            read_stats.append(rec.seq(), rec.qual(), &self.pats);
+        }
+        let result = filter_read(
+            rec.seq(),
+            rec.qual(),
+            &self.pats,
+            self.min_peak_qual,
+            self.min_mean_qual,
+        );
+        match result {
+            Ok(_) => self.stats.matching_reads += 1,
+            Err(Rejection::PeakQual) => {
+                self.stats.matching_reads += 1;
+                self.stats.peak_rejected += 1;
+            }
+            Err(Rejection::MeanQual) => {
+                self.stats.matching_reads += 1;
+                self.stats.mean_rejected += 1;
+            }
+            Err(Rejection::Ambiguous) => self.stats.ambigiuous_rejected += 1,
+            Err(Rejection::NoMatch) | Err(Rejection::NonDnaInsert) => {}
+        }
+        result.ok()
+    }
+
+    /// Lean alternative to collecting the `SearchMatch` items when only the counts are needed
+    ///
+    /// Neither quality nor sequence are copied per read: reverse strand matches are complemented into a reused buffer
+    /// and the counter is looked up with the borrowed slice, so only newly observed variants allocate.
+    pub fn count_into(&mut self, counter: &mut Counter<Vec<u8>>) {
+        let mut buf = Vec::new();
+        while let Some(rec) = self.records.next().and_then(IntoRecord::into_record) {
+            if let Some(result) = self.filter_record(&rec) {
+                count_seq(counter, result.oriented_seq(&mut buf));
+            }
         }
     }
+
+    /// Feeds every accepted match into `sink` and finishes it once the input is exhausted
+    pub fn run<S: MatchSink + ?Sized>(&mut self, sink: &mut S) -> io::Result<()> {
+        self.feed(sink)?;
+        sink.finish()
+    }
+
+    /// Feeds every accepted match into `sink` without finishing it, e.g. to combine several inputs
+    pub fn feed<S: MatchSink + ?Sized>(&mut self, sink: &mut S) -> io::Result<()> {
+        while let Some(rec) = self.records.next().and_then(IntoRecord::into_record) {
+            if let Some(result) = self.filter_record(&rec) {
+                sink.consume_with_id(rec.id(), &result.materialize())?;
+            }
+        }
+        Ok(())
+    }
 }
 
-impl<'a, T> Iterator for ReadFilter<'a, T>
+impl<'a, I> Iterator for ReadFilter<'a, I>
 where
-    T: io::BufRead,
+    I: Iterator,
+    I::Item: IntoRecord,
 {
     type Item = SearchMatch;
 
     fn next(&mut self) -> Option<Self::Item> {
         let res = loop {
-            let rec = self.fq_records.next()?.ok()?; // ParseErrors are silenced!
-            self.stats.total_reads += 1;
-            let provisional = match match_both_strands(&rec, &self.pats) {
-                (Some(a), None) => Some(a),
-                (None, Some(b)) => Some(b),
-                (Some(_), Some(_)) => {
-                    self.stats.ambigiuous_rejected += 1;
-                    None
-                } // TODO: How to deal with this illegal situation?
-                _ => None,
-            };
-            if let Some(result) = provisional {
-                if !result.seq().iter().all(is_dna_char) {
-                    continue;
-                }
-                self.stats.matching_reads += 1;
-                if let Some(min) = self.min_peak_qual {
-                    if result.peak_qual() < min {
-                        self.stats.peak_rejected += 1;
-                        continue;
-                    }
-                }
-                if let Some(min) = self.min_mean_qual {
-                    if result.mean_qual() < min {
-                        self.stats.mean_rejected += 1;
-                        continue;
-                    }
-                }
-
+            let rec = self.records.next()?.into_record()?;
+            if let Some(result) = self.filter_record(&rec) {
                 // First heap allocs after fastq parse happen here
                 break result.materialize();
             } // Else loop again till match or exhaustion
@@ -133,11 +194,74 @@ where
     }
 }
 
+/// Reason why a read did not yield an accepted insert
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// Flanks not found at the expected position on either strand
+    NoMatch,
+    /// Flanks found on both strands
+    Ambiguous,
+    /// Insert contains characters other than A, C, G or T
+    NonDnaInsert,
+    /// Lowest quality of the insert below the required `min_peak_qual`
+    PeakQual,
+    /// Average quality of the insert below the required `min_mean_qual`
+    MeanQual,
+}
+
+/// Extracts the insert of a single read and applies the quality thresholds
+pub fn filter_read<'a>(
+    read_seq: &'a [u8],
+    read_qual: &'a [u8],
+    patterns: &PrecomputedPatterns,
+    min_peak_qual: Option<u8>,
+    min_mean_qual: Option<u8>,
+) -> Result<CandidateMatch<'a>, Rejection> {
+    let result = match match_seq_both_strands(read_seq, read_qual, patterns) {
+        (Some(a), None) => a,
+        (None, Some(b)) => b,
+        (Some(_), Some(_)) => return Err(Rejection::Ambiguous), // TODO: How to deal with this illegal situation?
+        (None, None) => return Err(Rejection::NoMatch),
+    };
+    if !result.seq().iter().all(is_dna_char) {
+        return Err(Rejection::NonDnaInsert);
+    }
+    check_quality(&result, min_peak_qual, min_mean_qual)?;
+    Ok(result)
+}
+
+/// Applies the optional quality thresholds to an extracted insert
+pub fn check_quality(
+    candidate: &CandidateMatch,
+    min_peak_qual: Option<u8>,
+    min_mean_qual: Option<u8>,
+) -> Result<(), Rejection> {
+    if let Some(min) = min_peak_qual {
+        if candidate.peak_qual() < min {
+            return Err(Rejection::PeakQual);
+        }
+    }
+    if let Some(min) = min_mean_qual {
+        if candidate.mean_qual() < min {
+            return Err(Rejection::MeanQual);
+        }
+    }
+    Ok(())
+}
+
 pub fn match_both_strands<'a>(
     read: &'a bio::io::fastq::Record,
     patterns: &PrecomputedPatterns,
 ) -> (Option<CandidateMatch<'a>>, Option<CandidateMatch<'a>>) {
-    let read_seq = read.seq();
+    match_seq_both_strands(read.seq(), read.qual(), patterns)
+}
+
+/// Same as `match_both_strands` on the sequence and quality of a read stored elsewhere
+pub fn match_seq_both_strands<'a>(
+    read_seq: &'a [u8],
+    read_qual: &'a [u8],
+    patterns: &PrecomputedPatterns,
+) -> (Option<CandidateMatch<'a>>, Option<CandidateMatch<'a>>) {
     let read_len = read_seq.len();
 
     let mat_fwd = patterns.fwd_start.find_all(read_seq).find(|&idx| {
@@ -152,7 +276,7 @@ pub fn match_both_strands<'a>(
         let range = start_idx..start_idx + patterns.content_len;
         let mat = CandidateMatch::new(
             &read_seq[range.clone()],
-            &read.qual()[range],
+            &read_qual[range],
             false,
             start_idx as u32,
         );
@@ -164,7 +288,7 @@ pub fn match_both_strands<'a>(
     let mat_rev = patterns.rev_end.find_all(read_seq).find(
         |&idx| {
             (idx + patterns.total_len + patterns.expt_begin <= read_len)
-                && (idx + patterns.total_len + patterns.expt_end >= read_len)
+                && (read_len - (idx + patterns.total_len) <= patterns.expt_end)
                 && (read_seq[idx + patterns.rev_dist..idx + patterns.total_len]
                     == patterns.rev_start)
         }, // Legal due to first condition
@@ -174,7 +298,7 @@ pub fn match_both_strands<'a>(
         let range = idx + patterns.end_len..idx + patterns.rev_dist;
         let mat = CandidateMatch::new(
             &read_seq[range.clone()],
-            &read.qual()[range],
+            &read_qual[range],
             true,
             start_pos as u32,
         );
@@ -185,3 +309,50 @@ pub fn match_both_strands<'a>(
 
     (fwd, rev)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FilterBuilder;
+
+    #[test]
+    fn test_count_into_matches_materialized() {
+        let fq = b"@fwd\n\
+            AAAAAGGGCCAGTTTTTGCCCAGGCAAAA\n+\n\
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIII\n\
+            @rev\n\
+            TTTTGCCTGGGCAAAAACTGGCCCTTTTT\n+\n\
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIII\n\
+            @none\n\
+            AAAAAAAAAAAAAAAAAAAAAAAAAAAAA\n+\n\
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIII\n";
+        let cfg = FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+            .window(4, 2)
+            .build()
+            .unwrap();
+        let mut stats = RunningStats::default();
+        let materialized: Counter<Vec<u8>> =
+            ReadFilter::new(fastq::Reader::new(&fq[..]).records(), &cfg, &mut stats)
+                .map(|a| a.seq)
+                .collect();
+        let mut lean_stats = RunningStats::default();
+        let mut lean = Counter::new();
+        ReadFilter::new(fastq::Reader::new(&fq[..]).records(), &cfg, &mut lean_stats)
+            .count_into(&mut lean);
+        assert_eq!(materialized[&b"TTTTT".to_vec()], 2);
+        assert_eq!(materialized, lean);
+        assert_eq!(stats.total_reads, 3);
+        assert_eq!(lean_stats.matching_reads, 2);
+
+        let mut read_stats = ReadStats::new();
+        ReadFilter::new(fastq::Reader::new(&fq[..]).records(), &cfg, &mut lean_stats)
+            .with_read_stats(&mut read_stats)
+            .count_into(&mut lean);
+        let mut report = Vec::new();
+        read_stats.write_to_buf(&mut report, cfg.window()).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("# reads_without_left_flank: 1\n"));
+        assert!(report.contains("read_length\t29\t3\n"));
+        assert!(report.contains("left_flank_pos_fwd\t4\t1\nleft_flank_pos_rev\t4\t1\n"));
+    }
+}
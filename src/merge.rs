@@ -0,0 +1,276 @@
+//! Reading the counts tables of several samples and merging them into one matrix
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::stat::RunningStats;
+
+/// Counts table of a single sample as written by `read_filter` or `read_filter count`
+#[derive(Debug, Default)]
+pub struct CountsTable {
+    /// Regex of the `# filter:` header
+    pub filter: String,
+    pub peak_qual: u8,
+    pub mean_qual: u8,
+    pub stats: RunningStats,
+    /// Total of the `# counted_reads` header, also covering inserts left out of the table
+    pub total_counted: Option<u64>,
+    pub counts: Vec<(String, u64)>,
+}
+
+impl CountsTable {
+    /// Parses the `#` headers and the `seq\treads` table, unknown headers and further columns are skipped
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, Box<dyn Error>> {
+        let mut table = CountsTable::default();
+        let mut has_filter = false;
+        let mut in_table = false;
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let invalid = |msg: &str| format!("Line {}: {}", i + 1, msg);
+            if let Some(header) = line.strip_prefix("# ") {
+                let (key, value) = match header.split_once(": ") {
+                    Some(pair) => pair,
+                    None => continue,
+                };
+                let count = || {
+                    value
+                        .parse::<u32>()
+                        .map_err(|e| invalid(&format!("Invalid {}: {}", key, e)))
+                };
+                match key {
+                    "filter" => {
+                        table.filter = value.to_string();
+                        has_filter = true;
+                    }
+                    "accepted_peak_qual" => table.peak_qual = count()? as u8,
+                    "accepted_mean_qual" => table.mean_qual = count()? as u8,
+                    "raw_total_reads" => table.stats.total_reads = count()?,
+                    "matching_reads" => table.stats.matching_reads = count()?,
+                    "peak_qual_rejected_reads" => table.stats.peak_rejected = count()?,
+                    "mean_qual_rejected_reads" => table.stats.mean_rejected = count()?,
+                    "ambiguous_matches_rejected" => table.stats.ambigiuous_rejected = count()?,
+                    "counted_reads" => {
+                        table.total_counted = Some(
+                            value
+                                .parse()
+                                .map_err(|e| invalid(&format!("Invalid {}: {}", key, e)))?,
+                        )
+                    }
+                    _ => {}
+                }
+            } else if !in_table {
+                if !line
+                    .split('\t')
+                    .take(2)
+                    .eq(["seq", "reads"].iter().copied())
+                {
+                    return Err(invalid("Expected the column names seq and reads").into());
+                }
+                in_table = true;
+            } else {
+                let mut columns = line.split('\t');
+                let (seq, reads) = columns
+                    .next()
+                    .zip(columns.next())
+                    .ok_or_else(|| invalid("Expected two columns"))?;
+                let reads = reads
+                    .parse()
+                    .map_err(|e| invalid(&format!("Invalid count: {}", e)))?;
+                table.counts.push((seq.to_string(), reads));
+            }
+        }
+        if !has_filter {
+            return Err("Missing the # filter header".into());
+        }
+        if !in_table {
+            return Err("Missing the counts table".into());
+        }
+        Ok(table)
+    }
+
+    /// Reads a plain or compressed counts table
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let (reader, _compression) = niffler::from_path(path)?;
+        Self::from_reader(BufReader::new(reader))
+    }
+
+    /// Reads of all counted inserts, the sum of the listed ones for tables without `# counted_reads`
+    pub fn counted_reads(&self) -> u64 {
+        self.total_counted
+            .unwrap_or_else(|| self.counts.iter().map(|(_, reads)| reads).sum())
+    }
+}
+
+/// Part of the filter regex given by the flanks and the insert length, independent of the positional window
+fn insert_pattern(filter: &str) -> &str {
+    let pattern = filter.trim_start_matches('^');
+    if let Some(rest) = pattern.strip_prefix(".{") {
+        rest.split_once('}').map_or(rest, |(_, rest)| rest)
+    } else {
+        pattern.trim_start_matches(".*")
+    }
+}
+
+/// Counts of several samples
+pub struct CountMatrix {
+    samples: Vec<(String, CountsTable)>,
+    /// One count per sample, ordered by the inserts
+    rows: BTreeMap<String, Vec<u64>>,
+}
+
+impl CountMatrix {
+    /// Checks that the tables were produced by compatible filters and joins them by the inserts
+    ///
+    /// Without `lenient` also the positional window and the quality thresholds have to match.
+    pub fn merge(
+        samples: Vec<(String, CountsTable)>,
+        lenient: bool,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (first_name, first) = samples.first().ok_or("No counts table to merge")?;
+        for (name, table) in &samples[1..] {
+            if insert_pattern(&table.filter) != insert_pattern(&first.filter) {
+                return Err(format!(
+                    "Incompatible flanks or insert lengths of {} ({}) and {} ({})",
+                    first_name, first.filter, name, table.filter
+                )
+                .into());
+            }
+            let same_thresholds = (table.filter.as_str(), table.peak_qual, table.mean_qual)
+                == (first.filter.as_str(), first.peak_qual, first.mean_qual);
+            if !lenient && !same_thresholds {
+                return Err(format!(
+                    "Different positional window or quality thresholds of {} and {}, use --lenient to merge anyway",
+                    first_name, name
+                )
+                .into());
+            }
+        }
+        let mut rows: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+        for (i, (_, table)) in samples.iter().enumerate() {
+            for (seq, reads) in &table.counts {
+                rows.entry(seq.clone())
+                    .or_insert_with(|| vec![0; samples.len()])[i] += reads;
+            }
+        }
+        Ok(CountMatrix { samples, rows })
+    }
+
+    /// Names and tables in the given order
+    pub fn samples(&self) -> &[(String, CountsTable)] {
+        &self.samples
+    }
+
+    /// Inserts in lexicographic order with one count per sample
+    pub fn rows(&self) -> impl Iterator<Item = (&String, &Vec<u64>)> {
+        self.rows.iter()
+    }
+
+    /// One header line per sample with its filter and totals
+    pub fn write_sample_headers<W: io::Write>(&self, buf: &mut W) -> io::Result<()> {
+        for (name, table) in &self.samples {
+            let stats = &table.stats;
+            writeln!(
+                buf,
+                "# sample: {name}\tfilter={filter}\taccepted_peak_qual={peak_qual}\t\
+                    accepted_mean_qual={mean_qual}\traw_total_reads={total_reads}\t\
+                    matching_reads={matching_reads}\tquality_reads={qual_reads}\tcounted_reads={counted}",
+                name = name,
+                filter = table.filter,
+                peak_qual = table.peak_qual,
+                mean_qual = table.mean_qual,
+                total_reads = stats.total_reads,
+                matching_reads = stats.matching_reads,
+                qual_reads = stats
+                    .matching_reads
+                    .saturating_sub(stats.peak_rejected + stats.mean_rejected),
+                counted = table.counted_reads(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Matrix of the inserts by the samples
+    pub fn write_wide<W: io::Write>(&self, buf: &mut W) -> io::Result<()> {
+        self.write_sample_headers(buf)?;
+        write!(buf, "seq")?;
+        for (name, _) in &self.samples {
+            write!(buf, "\t{}", name)?;
+        }
+        writeln!(buf)?;
+        for (seq, counts) in &self.rows {
+            write!(buf, "{}", seq)?;
+            for reads in counts {
+                write!(buf, "\t{}", reads)?;
+            }
+            writeln!(buf)?;
+        }
+        Ok(())
+    }
+
+    /// One line per insert and sample with a nonzero count
+    pub fn write_long<W: io::Write>(&self, buf: &mut W) -> io::Result<()> {
+        self.write_sample_headers(buf)?;
+        writeln!(buf, "seq\tsample\treads")?;
+        for (seq, counts) in &self.rows {
+            for ((name, _), reads) in self.samples.iter().zip(counts) {
+                if *reads > 0 {
+                    writeln!(buf, "{}\t{}\t{}", seq, name, reads)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FilterBuilder;
+    use crate::output::{write_config_header, write_stats_header};
+
+    fn table(window: (u32, u32), counts: &[(&str, u64)]) -> CountsTable {
+        let cfg = FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+            .window(window.0, window.1)
+            .build()
+            .unwrap();
+        let stats = RunningStats {
+            total_reads: 10,
+            matching_reads: 8,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_config_header(&mut buf, &cfg).unwrap();
+        write_stats_header(&mut buf, &stats).unwrap();
+        buf.extend_from_slice(b"seq\treads\n");
+        for (seq, reads) in counts {
+            buf.extend_from_slice(format!("{}\t{}\n", seq, reads).as_bytes());
+        }
+        CountsTable::from_reader(&buf[..]).unwrap()
+    }
+
+    #[test]
+    fn test_merge_tables() {
+        let s1 = table((4, 2), &[("AAAAA", 5), ("CCCCC", 3)]);
+        assert_eq!(s1.stats.matching_reads, 8);
+        assert_eq!(s1.counted_reads(), 8);
+        let s2 = table((4, 2), &[("CCCCC", 1), ("GGGGG", 2)]);
+        let matrix = CountMatrix::merge(vec![("s1".into(), s1), ("s2".into(), s2)], false).unwrap();
+        let mut wide = Vec::new();
+        matrix.write_wide(&mut wide).unwrap();
+        let wide = String::from_utf8(wide).unwrap();
+        assert!(wide.ends_with("seq\ts1\ts2\nAAAAA\t5\t0\nCCCCC\t3\t1\nGGGGG\t0\t2\n"));
+        let mut long = Vec::new();
+        matrix.write_long(&mut long).unwrap();
+        assert_eq!(String::from_utf8(long).unwrap().lines().count(), 2 + 1 + 4);
+
+        let shifted = || {
+            vec![
+                ("a".into(), table((4, 2), &[])),
+                ("b".into(), table((10, 2), &[])),
+            ]
+        };
+        assert!(CountMatrix::merge(shifted(), false).is_err());
+        assert!(CountMatrix::merge(shifted(), true).is_ok());
+    }
+}
@@ -0,0 +1,108 @@
+//! Custom content files picked up by MultiQC without a plugin
+//!
+//! Every file holds a single plot of one sample, MultiQC joins the files with the same `id` across samples.
+use serde_json::{json, Value};
+
+use crate::diversity::Diversity;
+use crate::stat::{QualStats, RunningStats};
+
+/// Bar graph of the fate of all reads
+pub fn funnel(sample: &str, stats: &RunningStats) -> Value {
+    let accepted = stats.matching_reads - (stats.peak_rejected + stats.mean_rejected);
+    let no_match = stats.total_reads - (stats.matching_reads + stats.ambigiuous_rejected);
+    json!({
+        "id": "read_filter_funnel",
+        "section_name": "read_filter read accounting",
+        "description": "Fate of the reads in the insert extraction and quality filtering",
+        "plot_type": "bargraph",
+        "pconfig": {
+            "id": "read_filter_funnel_plot",
+            "title": "read_filter: Read accounting",
+            "ylab": "Reads",
+        },
+        "data": {
+            sample: {
+                "accepted": accepted,
+                "peak_qual_rejected": stats.peak_rejected,
+                "mean_qual_rejected": stats.mean_rejected,
+                "ambiguous_rejected": stats.ambigiuous_rejected,
+                "no_match": no_match,
+            }
+        },
+    })
+}
+
+/// Line plot of the mean quality along the insert
+pub fn quality_profile(sample: &str, qual_stats: &QualStats) -> Value {
+    // Pairs of position and quality keep their order, unlike keys of a JSON object
+    let profile: Vec<(usize, f64)> = qual_stats
+        .position_means()
+        .into_iter()
+        .map(|mean| (mean * 100.).round() / 100.)
+        .enumerate()
+        .collect();
+    json!({
+        "id": "read_filter_quality",
+        "section_name": "read_filter insert quality",
+        "description": "Mean PHRED score at each position of the accepted inserts",
+        "plot_type": "linegraph",
+        "pconfig": {
+            "id": "read_filter_quality_plot",
+            "title": "read_filter: Insert quality profile",
+            "xlab": "Position in the insert",
+            "ylab": "Mean PHRED score",
+            "ymin": 0,
+        },
+        "data": { sample: profile },
+    })
+}
+
+/// Table of the diversity metrics
+pub fn diversity_table(sample: &str, diversity: &Diversity) -> Value {
+    let mut row = json!({
+        "unique_inserts": diversity.unique_inserts,
+        "shannon_entropy": diversity.shannon_entropy,
+        "simpson_index": diversity.simpson_index,
+        "chao1": diversity.chao1,
+    });
+    for (n, fraction) in &diversity.top_fractions {
+        row[format!("top_{}_fraction", n)] = json!(fraction);
+    }
+    if let Some(design) = &diversity.design {
+        row["design_coverage"] = json!(design.coverage);
+        row["reads_in_design"] = json!(design.reads_in_design);
+    }
+    json!({
+        "id": "read_filter_diversity",
+        "section_name": "read_filter library diversity",
+        "description": "Diversity of the counted inserts",
+        "plot_type": "table",
+        "pconfig": {
+            "id": "read_filter_diversity_table",
+            "title": "read_filter: Library diversity",
+        },
+        "data": { sample: row },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_funnel_adds_up() {
+        let stats = RunningStats {
+            total_reads: 100,
+            matching_reads: 80,
+            ambigiuous_rejected: 5,
+            peak_rejected: 10,
+            mean_rejected: 3,
+        };
+        let funnel = funnel("s1", &stats);
+        let data = funnel["data"]["s1"].as_object().unwrap();
+        let total: u64 = data.values().map(|v| v.as_u64().unwrap()).sum();
+        assert_eq!(total, 100);
+        assert_eq!(data["accepted"], 67);
+        assert_eq!(data["no_match"], 15);
+    }
+}
@@ -0,0 +1,585 @@
+//! Names of the output files derived from a sample name and a filename template
+use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Reproduces the names of earlier versions, e.g. `sample.processed.tsv`
+pub const DEFAULT_TEMPLATE: &str = "{sample}.{kind}.{ext}";
+
+/// Compression suffixes recognized when deriving the sample name from an input file
+const COMPRESSION_ENDINGS: [&str; 5] = [".gz", ".bgz", ".bz2", ".zst", ".xz"];
+/// Read format suffixes recognized when deriving the sample name from an input file
+const READ_ENDINGS: [&str; 9] = [
+    ".fastq", ".fq", ".txt", ".fasta", ".fa", ".fna", ".fas", ".sam", ".bam",
+];
+
+/// Files written by the command line tool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Counts of the inserts with the header of config and stats
+    Processed,
+    ReadReport,
+    Quality,
+    /// Quality distribution per position of the insert
+    PositionQuality,
+    /// FASTQ export of the accepted inserts
+    Matches,
+    /// Diversity metrics as JSON
+    Diversity,
+    /// Whole-read QC
+    ReadQc,
+    /// MultiQC custom content of the read accounting
+    MultiqcFunnel,
+    /// MultiQC custom content of the insert quality profile
+    MultiqcQuality,
+    /// MultiQC custom content of the diversity metrics
+    MultiqcDiversity,
+    /// Self-contained HTML QC report
+    Html,
+    /// Provenance of the run and the list of its outputs
+    Provenance,
+}
+
+impl OutputKind {
+    pub const ALL: [OutputKind; 12] = [
+        OutputKind::Processed,
+        OutputKind::ReadReport,
+        OutputKind::Quality,
+        OutputKind::PositionQuality,
+        OutputKind::Matches,
+        OutputKind::Diversity,
+        OutputKind::ReadQc,
+        OutputKind::MultiqcFunnel,
+        OutputKind::MultiqcQuality,
+        OutputKind::MultiqcDiversity,
+        OutputKind::Html,
+        OutputKind::Provenance,
+    ];
+
+    /// Value of the `{kind}` placeholder
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputKind::Processed => "processed",
+            OutputKind::ReadReport => "readreport",
+            OutputKind::Quality => "quality",
+            OutputKind::PositionQuality => "positionquality",
+            OutputKind::Matches => "matches",
+            OutputKind::Diversity => "diversity",
+            OutputKind::ReadQc => "readqc",
+            // MultiQC picks up files ending with `_mqc.json`
+            OutputKind::MultiqcFunnel => "funnel_mqc",
+            OutputKind::MultiqcQuality => "quality_mqc",
+            OutputKind::MultiqcDiversity => "diversity_mqc",
+            OutputKind::Html => "report",
+            OutputKind::Provenance => "provenance",
+        }
+    }
+
+    /// Value of the `{ext}` placeholder
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputKind::Matches => "fq",
+            OutputKind::Html => "html",
+            OutputKind::Diversity
+            | OutputKind::Provenance
+            | OutputKind::MultiqcFunnel
+            | OutputKind::MultiqcQuality
+            | OutputKind::MultiqcDiversity => "json",
+            _ => "tsv",
+        }
+    }
+}
+
+impl fmt::Display for OutputKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for OutputKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = OutputKind::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == s);
+        kind.ok_or_else(|| {
+            let names: Vec<_> = OutputKind::ALL.iter().map(|kind| kind.name()).collect();
+            format!("Unknown output {:?}, use one of {}", s, names.join(", ")).into()
+        })
+    }
+}
+
+/// Compression of an output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Appended to the file name if not already present
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Inferred from the extension of a file name, e.g. from a template ending with `.gz`
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|e| e.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    /// Default levels of the gzip and zstd command line tools
+    pub fn level(&self) -> u32 {
+        match self {
+            Compression::Zstd => 3,
+            _ => 6,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gz" | "gzip" => Ok(Compression::Gzip),
+            "zst" | "zstd" => Ok(Compression::Zstd),
+            _ => Err(format!("Unknown compression {:?}, use gz, zstd or none", s).into()),
+        }
+    }
+}
+
+/// Format of the counts table and the read report
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TableFormat {
+    /// Tab-separated with the config and stats as `#` header lines
+    #[default]
+    Tsv,
+    /// Header fields and column arrays in one object, JSON Lines for the read report
+    Json,
+    Parquet,
+    /// Arrow IPC file
+    Arrow,
+}
+
+impl TableFormat {
+    /// Value of the `{ext}` placeholder for `kind` in this format
+    pub fn extension(&self, kind: OutputKind) -> &'static str {
+        match (self, kind) {
+            (TableFormat::Tsv, _) => kind.extension(),
+            (TableFormat::Json, OutputKind::ReadReport) => "jsonl",
+            (TableFormat::Json, _) => "json",
+            (TableFormat::Parquet, _) => "parquet",
+            (TableFormat::Arrow, _) => "arrow",
+        }
+    }
+
+    /// Binary formats compressing their columns by themselves
+    pub fn is_columnar(&self) -> bool {
+        matches!(self, TableFormat::Parquet | TableFormat::Arrow)
+    }
+}
+
+impl FromStr for TableFormat {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = match s {
+            "tsv" => TableFormat::Tsv,
+            "json" => TableFormat::Json,
+            "parquet" => TableFormat::Parquet,
+            "arrow" => TableFormat::Arrow,
+            _ => {
+                return Err(format!(
+                    "Unknown table format {:?}, use tsv, json, parquet or arrow",
+                    s
+                )
+                .into())
+            }
+        };
+        if format.is_columnar() && !cfg!(feature = "columnar") {
+            return Err(format!("The {} format requires the columnar feature", s).into());
+        }
+        Ok(format)
+    }
+}
+
+/// Compression requested on the command line for all or single outputs
+///
+/// Outputs without a choice infer it from their file name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressionChoice {
+    all: Option<Compression>,
+    per_kind: Vec<(OutputKind, Compression)>,
+}
+
+impl CompressionChoice {
+    /// Adds a choice given as `FORMAT` for all outputs or `KIND=FORMAT` for a single one
+    pub fn push_arg(&mut self, arg: &str) -> Result<(), Box<dyn Error>> {
+        match arg.split_once('=') {
+            Some((kind, format)) => self.per_kind.push((kind.parse()?, format.parse()?)),
+            None => self.all = Some(arg.parse()?),
+        }
+        Ok(())
+    }
+
+    /// The choice for a single output takes precedence over the one for all outputs
+    pub fn get(&self, kind: OutputKind) -> Option<Compression> {
+        self.get_single(kind).or(self.all)
+    }
+
+    /// The choice given for this output only
+    fn get_single(&self, kind: OutputKind) -> Option<Compression> {
+        self.per_kind
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, c)| *c)
+    }
+}
+
+/// Strips all known read format and compression suffixes from the file name of `path`
+///
+/// Repeated suffixes like `sample.fastq.gz.fastq.gz` are removed as well,
+/// a name consisting only of suffixes or without any known suffix is kept as it is.
+/// Returns `None` if `path` has no file name, e.g. `..`.
+pub fn sample_name_from_path<P: AsRef<Path>>(path: P) -> Option<String> {
+    let name = path.as_ref().file_name()?.to_str()?;
+    let mut stem = name;
+    while let Some(stripped) = COMPRESSION_ENDINGS
+        .iter()
+        .chain(READ_ENDINGS.iter())
+        .find_map(|e| stem.strip_suffix(e))
+    {
+        stem = stripped;
+    }
+    if stem.is_empty() {
+        stem = name;
+    }
+    Some(stem.to_string())
+}
+
+/// Strips compression suffixes, `.tsv` and `.processed` from the file name of a counts table
+///
+/// Returns `None` if `path` has no file name.
+pub fn sample_name_from_table<P: AsRef<Path>>(path: P) -> Option<String> {
+    let name = path.as_ref().file_name()?.to_str()?;
+    let stem = COMPRESSION_ENDINGS
+        .iter()
+        .find_map(|e| name.strip_suffix(e))
+        .unwrap_or(name);
+    let stem = stem.strip_suffix(".tsv").unwrap_or(stem);
+    let kind = format!(".{}", OutputKind::Processed.name());
+    Some(stem.strip_suffix(&kind).unwrap_or(stem).to_string())
+}
+
+/// Checks that a sample name can be used as part of a file name in the output directory
+pub fn check_sample_name(name: &str) -> Result<(), Box<dyn Error>> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(std::path::is_separator) {
+        return Err(format!("Sample name {:?} has to be a file name", name).into());
+    }
+    Ok(())
+}
+
+/// Filename template with the placeholders `{sample}`, `{kind}` and `{ext}`
+///
+/// `{kind}` is required to tell the outputs of one run apart.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate(String);
+
+impl OutputTemplate {
+    pub fn new(template: &str) -> Result<Self, Box<dyn Error>> {
+        if template.contains(std::path::is_separator) {
+            return Err(format!("Output template {:?} has to be a file name", template).into());
+        }
+        let mut rest = template;
+        let mut has_kind = false;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in output template {:?}", template))?;
+            match &rest[start..start + end + 1] {
+                "{kind}" => has_kind = true,
+                "{sample}" | "{ext}" => {}
+                other => {
+                    return Err(format!(
+                        "Unknown placeholder {} in output template {:?}, use {{sample}}, {{kind}} or {{ext}}",
+                        other, template
+                    )
+                    .into())
+                }
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if !has_kind {
+            return Err(format!("Output template {:?} lacks {{kind}}", template).into());
+        }
+        Ok(OutputTemplate(template.to_string()))
+    }
+
+    pub fn file_name(&self, sample: &str, kind: OutputKind) -> String {
+        self.file_name_with_ext(sample, kind, kind.extension())
+    }
+
+    /// Substitutes the placeholders in a single pass, braces in `sample` are kept as they are
+    fn file_name_with_ext(&self, sample: &str, kind: OutputKind, ext: &str) -> String {
+        let mut name = String::with_capacity(self.0.len() + sample.len());
+        let mut rest = self.0.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = start
+                + rest[start..]
+                    .find('}')
+                    .expect("Checked by OutputTemplate::new");
+            name.push_str(&rest[..start]);
+            name.push_str(match &rest[start..=end] {
+                "{sample}" => sample,
+                "{kind}" => kind.name(),
+                _ => ext,
+            });
+            rest = &rest[end + 1..];
+        }
+        name.push_str(rest);
+        name
+    }
+
+    pub fn path<P: AsRef<Path>>(&self, outdir: P, sample: &str, kind: OutputKind) -> PathBuf {
+        outdir.as_ref().join(self.file_name(sample, kind))
+    }
+
+    /// Path and compression of an output, the suffix of a chosen compression is appended if missing
+    pub fn compressed_path<P: AsRef<Path>>(
+        &self,
+        outdir: P,
+        sample: &str,
+        kind: OutputKind,
+        choice: &CompressionChoice,
+    ) -> (PathBuf, Compression) {
+        let name = self.file_name(sample, kind);
+        Self::compress_name(outdir, name, kind, choice)
+    }
+
+    /// Like `compressed_path` with the extension of `format`, columnar formats are never compressed on top
+    ///
+    /// The compression chosen for all outputs skips columnar tables,
+    /// a choice for the table alone or a compression suffix is refused by `check_compression`.
+    pub fn table_path<P: AsRef<Path>>(
+        &self,
+        outdir: P,
+        sample: &str,
+        kind: OutputKind,
+        format: TableFormat,
+        choice: &CompressionChoice,
+    ) -> (PathBuf, Compression) {
+        let name = self.file_name_with_ext(sample, kind, format.extension(kind));
+        if format.is_columnar() {
+            return (outdir.as_ref().join(name), Compression::None);
+        }
+        Self::compress_name(outdir, name, kind, choice)
+    }
+
+    /// Refuses to write uncompressed outputs named like compressed ones, e.g. `-z none` with a template ending in `.gz`,
+    /// and tables in a columnar `format` compressed by a choice of their own or the template
+    pub fn check_compression(
+        &self,
+        choice: &CompressionChoice,
+        format: TableFormat,
+    ) -> Result<(), Box<dyn Error>> {
+        for kind in OutputKind::ALL {
+            let is_table = matches!(kind, OutputKind::Processed | OutputKind::ReadReport);
+            if is_table && format.is_columnar() {
+                let name = self.file_name_with_ext("sample", kind, format.extension(kind));
+                let single = choice.get_single(kind).unwrap_or(Compression::None);
+                if single != Compression::None || Compression::from_path(&name) != Compression::None
+                {
+                    return Err(format!(
+                        "Parquet and Arrow outputs are compressed internally, leave out the compression of {} by --compress {}=FORMAT and the output template",
+                        kind, kind
+                    )
+                    .into());
+                }
+                continue;
+            }
+            let name = self.file_name("sample", kind);
+            if choice.get(kind) == Some(Compression::None)
+                && Compression::from_path(&name) != Compression::None
+            {
+                return Err(format!(
+                    "Output template {:?} names {} compressed, which the chosen compression none contradicts",
+                    self.0, name
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    fn compress_name<P: AsRef<Path>>(
+        outdir: P,
+        mut name: String,
+        kind: OutputKind,
+        choice: &CompressionChoice,
+    ) -> (PathBuf, Compression) {
+        let compression = match choice.get(kind) {
+            Some(compression) => {
+                if !name.ends_with(compression.suffix()) {
+                    name.push_str(compression.suffix());
+                }
+                compression
+            }
+            None => Compression::from_path(&name),
+        };
+        (outdir.as_ref().join(name), compression)
+    }
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        OutputTemplate(DEFAULT_TEMPLATE.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sample_name_strips_known_endings() {
+        for (path, name) in [
+            ("data/sample.fastq.gz", "sample"),
+            ("sample.fq.gz", "sample"),
+            ("sample.fastq.bz2", "sample"),
+            ("sample.fastq.zst", "sample"),
+            ("sample.fastq.gz.fastq.gz", "sample"),
+            ("sample_L001.txt.gz", "sample_L001"),
+            ("run.v2.bam", "run.v2"),
+            ("sample.reads", "sample.reads"),
+            (".fastq.gz", ".fastq.gz"),
+        ] {
+            assert_eq!(sample_name_from_path(path).unwrap(), name);
+        }
+        assert_eq!(sample_name_from_path(".."), None);
+        assert!(check_sample_name("s1_L001.v2").is_ok());
+        for name in ["", "..", "../s1", "out/s1"] {
+            assert!(check_sample_name(name).is_err());
+        }
+        assert_eq!(
+            sample_name_from_table("out/s1.processed.tsv.gz").unwrap(),
+            "s1"
+        );
+    }
+
+    #[test]
+    fn test_template() {
+        let default = OutputTemplate::default();
+        assert_eq!(
+            default.file_name("s1", OutputKind::Processed),
+            "s1.processed.tsv"
+        );
+        assert_eq!(
+            default.file_name("s1", OutputKind::Matches),
+            "s1.matches.fq"
+        );
+        let custom = OutputTemplate::new("{kind}_{sample}.tsv").unwrap();
+        assert_eq!(
+            custom.file_name("s1", OutputKind::Quality),
+            "quality_s1.tsv"
+        );
+        assert_eq!(
+            default.file_name("s{kind}{ext}", OutputKind::Processed),
+            "s{kind}{ext}.processed.tsv"
+        );
+        assert!(OutputTemplate::new("{sample}.tsv").is_err());
+        assert!(OutputTemplate::new("{sample}.{kind}.{suffix}").is_err());
+        assert!(OutputTemplate::new("{sample}.{kind").is_err());
+        assert!(OutputTemplate::new("out/{sample}.{kind}.tsv").is_err());
+    }
+
+    #[test]
+    fn test_compression_choice() {
+        let template = OutputTemplate::new("{sample}.{kind}.{ext}.gz").unwrap();
+        let mut choice = CompressionChoice::default();
+        let path = |t: &OutputTemplate, c: &CompressionChoice, kind| {
+            let (path, compression) = t.compressed_path("out", "s1", kind, c);
+            (path.to_str().unwrap().to_string(), compression)
+        };
+        assert_eq!(
+            path(&template, &choice, OutputKind::Processed),
+            ("out/s1.processed.tsv.gz".to_string(), Compression::Gzip)
+        );
+        choice.push_arg("readreport=zstd").unwrap();
+        choice.push_arg("none").unwrap();
+        let default = OutputTemplate::default();
+        assert_eq!(
+            path(&default, &choice, OutputKind::ReadReport),
+            ("out/s1.readreport.tsv.zst".to_string(), Compression::Zstd)
+        );
+        assert_eq!(
+            path(&default, &choice, OutputKind::Matches),
+            ("out/s1.matches.fq".to_string(), Compression::None)
+        );
+        assert!(template
+            .check_compression(&choice, TableFormat::Tsv)
+            .is_err());
+        assert!(default.check_compression(&choice, TableFormat::Tsv).is_ok());
+        assert!(choice.push_arg("counts=gz").is_err());
+        assert!(choice.push_arg("xz").is_err());
+    }
+
+    #[test]
+    fn test_table_path() {
+        let template = OutputTemplate::default();
+        let mut choice = CompressionChoice::default();
+        choice.push_arg("gz").unwrap();
+        let (path, compression) = template.table_path(
+            "out",
+            "s1",
+            OutputKind::ReadReport,
+            TableFormat::Json,
+            &choice,
+        );
+        assert_eq!(path, Path::new("out/s1.readreport.jsonl.gz"));
+        assert_eq!(compression, Compression::Gzip);
+        let (path, compression) = template.table_path(
+            "out",
+            "s1",
+            OutputKind::Processed,
+            TableFormat::Parquet,
+            &choice,
+        );
+        assert_eq!(path, Path::new("out/s1.processed.parquet"));
+        assert_eq!(compression, Compression::None);
+        // Compression for all outputs leaves the columnar tables out, asking for it for the table alone is refused
+        assert!(template
+            .check_compression(&choice, TableFormat::Parquet)
+            .is_ok());
+        choice.push_arg("processed=gz").unwrap();
+        assert!(template
+            .check_compression(&choice, TableFormat::Parquet)
+            .is_err());
+        assert!(template
+            .check_compression(&choice, TableFormat::Tsv)
+            .is_ok());
+        let suffixed = OutputTemplate::new("{sample}.{kind}.{ext}.zst").unwrap();
+        let none = CompressionChoice::default();
+        assert!(suffixed
+            .check_compression(&none, TableFormat::Arrow)
+            .is_err());
+        let mut per_kind = CompressionChoice::default();
+        per_kind.push_arg("quality=gz").unwrap();
+        assert!(template
+            .check_compression(&per_kind, TableFormat::Parquet)
+            .is_ok());
+    }
+}
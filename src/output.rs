@@ -2,58 +2,205 @@
 
 use std::io;
 
-use crate::config::ProgConfig;
+use counter::Counter;
+use serde_json::{json, Map, Value};
+
+use crate::config::{FilterConf, FilterParams};
 use crate::stat::RunningStats;
 
+/// Selection and columns of the rows of a counts table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CountsFormat {
+    /// Inserts with fewer reads are left out
+    pub min_count: usize,
+    /// Only the most abundant inserts
+    pub top: Option<usize>,
+    /// Adds the reads per million of all counted reads
+    pub rpm: bool,
+}
+
+/// Inserts selected by `format`, sorted by count in descending order with ties ordered by sequence
+pub fn sorted_counts<'a>(
+    counter: &'a Counter<Vec<u8>>,
+    format: &CountsFormat,
+) -> Vec<(&'a Vec<u8>, usize)> {
+    let mut rows: Vec<(&Vec<u8>, usize)> = counter
+        .iter()
+        .filter(|(_, &count)| count >= format.min_count)
+        .map(|(seq, &count)| (seq, count))
+        .collect();
+    rows.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    rows.truncate(format.top.unwrap_or(usize::MAX));
+    rows
+}
+
+/// Reads per million of all `total` counted reads
+pub fn reads_per_million(count: usize, total: usize) -> f64 {
+    count as f64 * 1e6 / total as f64
+}
+
+/// Writes the inserts as selected and sorted by `sorted_counts`
+///
+/// The `# counted_reads` header keeps the total of all inserts for tables shortened by `format`.
+pub fn write_counts_table<T: io::Write>(
+    buf: &mut T,
+    counter: &Counter<Vec<u8>>,
+    format: &CountsFormat,
+) -> io::Result<()> {
+    let total: usize = counter.values().sum();
+    let rows = sorted_counts(counter, format);
+    writeln!(buf, "# counted_reads: {}", total)?;
+    if format.rpm {
+        writeln!(buf, "seq\treads\trpm")?;
+    } else {
+        writeln!(buf, "seq\treads")?;
+    }
+    for (seq, count) in rows {
+        write!(buf, "{}\t{}", String::from_utf8_lossy(seq), count)?;
+        if format.rpm {
+            write!(buf, "\t{:.2}", reads_per_million(count, total))?;
+        }
+        writeln!(buf)?;
+    }
+    Ok(())
+}
+
+/// Names and values of the stats header lines, the per input lines leave out the last one
+fn stats_fields(stats: &RunningStats) -> [(&'static str, u32); 6] {
+    [
+        ("raw_total_reads", stats.total_reads),
+        ("matching_reads", stats.matching_reads),
+        ("peak_qual_rejected_reads", stats.peak_rejected),
+        ("mean_qual_rejected_reads", stats.mean_rejected),
+        ("ambiguous_matches_rejected", stats.ambigiuous_rejected),
+        (
+            "quality_reads",
+            stats.matching_reads - (stats.peak_rejected + stats.mean_rejected),
+        ),
+    ]
+}
+
 pub fn write_stats_header<T: io::Write>(buf: &mut T, stats: &RunningStats) -> io::Result<()> {
-    let qual_reads = stats.matching_reads - (stats.peak_rejected + stats.mean_rejected);
-    write!(
-        buf,
-        "# raw_total_reads: {total_reads}\n\
-            # matching_reads: {matching_reads}\n\
-            # peak_qual_rejected_reads: {peak_rejected}\n\
-            # mean_qual_rejected_reads: {mean_rejected}\n\
-            # ambiguous_matches_rejected: {ambiguous_rejected}\n\
-            # quality_reads: {qual_reads}\n",
-        total_reads = stats.total_reads,
-        matching_reads = stats.matching_reads,
-        peak_rejected = stats.peak_rejected,
-        mean_rejected = stats.mean_rejected,
-        ambiguous_rejected = stats.ambigiuous_rejected,
-        qual_reads = qual_reads,
-    )
+    for (name, value) in stats_fields(stats) {
+        writeln!(buf, "# {}: {}", name, value)?;
+    }
+    Ok(())
 }
 
-pub fn write_config_header<T: io::Write>(buf: &mut T, cfg: &ProgConfig) -> io::Result<()> {
-    // Writing the regex is to reflect the original python version, but no guarantee that we use the exact regex
-    // Code duplication or out of sync risk
-    let expt_begin = cfg.expected_start.saturating_sub(cfg.position_tolerance);
-    let expt_end = cfg.expected_start + cfg.position_tolerance;
-    let regex = format!(
-        "^.{{{expt_begin},{expt_end}}}{left_flank}([ACGT]{{{content_length}}}){right_flank}.*$",
-        expt_begin = expt_begin,
-        expt_end = expt_end,
+/// One line per input file with its share of the `RunningStats`
+pub fn write_input_stats_header<T: io::Write>(
+    buf: &mut T,
+    inputs: &[(String, RunningStats)],
+) -> io::Result<()> {
+    for (name, stats) in inputs {
+        write!(buf, "# input: {}", name)?;
+        for (field, value) in &stats_fields(stats)[..5] {
+            write!(buf, "\t{}={}", field, value)?;
+        }
+        writeln!(buf)?;
+    }
+    Ok(())
+}
+
+/// Writing the regex is to reflect the original python version, but no guarantee that we use the exact regex
+fn filter_regex(cfg: &FilterParams) -> String {
+    let prefix = match cfg.window() {
+        Some((expt_begin, expt_end)) => format!(".{{{},{}}}", expt_begin, expt_end),
+        None => ".*".to_string(),
+    };
+    format!(
+        "^{prefix}{left_flank}([ACGT]{{{content_length}}}){right_flank}.*$",
+        prefix = prefix,
         left_flank = cfg.left_flank,
         right_flank = cfg.right_flank,
         content_length = cfg.insert_length
-    );
+    )
+}
+
+pub fn write_config_header<T: io::Write>(buf: &mut T, cfg: &FilterParams) -> io::Result<()> {
     write!(
         buf,
         "# filter: {regex}\n\
             # accepted_peak_qual: {peak_accepted}\n\
             # accepted_mean_qual: {mean_accepted}\n",
-        regex = regex,
+        regex = filter_regex(cfg),
         peak_accepted = cfg.min_peak_qual.unwrap_or_default(),
         mean_accepted = cfg.min_mean_qual.unwrap_or_default(),
     )
 }
 
+/// The `#` header lines of config and stats as JSON, stored by the other table formats
+///
+/// The per input lines become the `inputs` array and the config is added as `config`.
+pub fn table_metadata(
+    cfg: &FilterParams,
+    stats: &RunningStats,
+    inputs: &[(String, RunningStats)],
+) -> Map<String, Value> {
+    let mut metadata = Map::new();
+    metadata.insert("filter".into(), json!(filter_regex(cfg)));
+    metadata.insert(
+        "accepted_peak_qual".into(),
+        json!(cfg.min_peak_qual.unwrap_or_default()),
+    );
+    metadata.insert(
+        "accepted_mean_qual".into(),
+        json!(cfg.min_mean_qual.unwrap_or_default()),
+    );
+    metadata.insert("config".into(), json!(FilterConf::from(cfg)));
+    let inputs = inputs
+        .iter()
+        .map(|(name, stats)| {
+            let mut input = Map::new();
+            input.insert("input".into(), json!(name));
+            for (field, value) in &stats_fields(stats)[..5] {
+                input.insert(field.to_string(), json!(value));
+            }
+            Value::Object(input)
+        })
+        .collect();
+    metadata.insert("inputs".into(), Value::Array(inputs));
+    for (field, value) in stats_fields(stats) {
+        metadata.insert(field.into(), json!(value));
+    }
+    metadata
+}
+
+/// Counts table as one JSON object: the `metadata` fields and `counts` with an array per column
+pub fn write_counts_json<T: io::Write>(
+    buf: &mut T,
+    counter: &Counter<Vec<u8>>,
+    format: &CountsFormat,
+    mut metadata: Map<String, Value>,
+) -> io::Result<()> {
+    let total: usize = counter.values().sum();
+    let rows = sorted_counts(counter, format);
+    let mut columns = Map::new();
+    let seqs: Vec<_> = rows
+        .iter()
+        .map(|(seq, _)| String::from_utf8_lossy(seq))
+        .collect();
+    columns.insert("seq".into(), json!(seqs));
+    let reads: Vec<_> = rows.iter().map(|(_, count)| *count).collect();
+    columns.insert("reads".into(), json!(reads));
+    if format.rpm {
+        let rpm: Vec<_> = reads
+            .iter()
+            .map(|&count| reads_per_million(count, total))
+            .collect();
+        columns.insert("rpm".into(), json!(rpm));
+    }
+    metadata.insert("counts".into(), Value::Object(columns));
+    serde_json::to_writer(&mut *buf, &metadata)?;
+    writeln!(buf)
+}
+
 pub fn write_read_report_header<T: io::Write>(buf: &mut T, seq_len: usize) -> io::Result<()> {
     write!(buf, "read\tdist_start\treversed\tpeak_qual\tmean_qual")?;
     for i in 0..seq_len {
         write!(buf, "\tqual_pos_{}", i)?;
     }
-    write!(buf, "\n")
+    writeln!(buf)
 }
 
 pub fn write_qual_report_header<T: io::Write>(buf: &mut T, seq_len: usize) -> io::Result<()> {
@@ -64,5 +211,51 @@ pub fn write_qual_report_header<T: io::Write>(buf: &mut T, seq_len: usize) -> io
     for i in 0..seq_len {
         write!(buf, "\tqual_pos_{}", i)?;
     }
-    write!(buf, "\n")
+    writeln!(buf)
+}
+
+/// Columns of the binned QC report, the bins are given as `lower-upper` or `lower+`
+pub fn write_binned_qual_report_header<T: io::Write>(
+    buf: &mut T,
+    seq_len: usize,
+) -> io::Result<()> {
+    write!(
+        buf,
+        "dist_start_bin\tpeak_qual_bin\tmean_qual_bin\treads\treverse_reads"
+    )?;
+    for i in 0..seq_len {
+        write!(buf, "\tqual_pos_{}", i)?;
+    }
+    writeln!(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_counts_table_is_sorted() {
+        let mut counter = Counter::new();
+        for (seq, count) in [("CCC", 2), ("GGG", 5), ("AAA", 2), ("TTT", 1)] {
+            counter.insert(seq.as_bytes().to_vec(), count);
+        }
+        let mut buf = Vec::new();
+        write_counts_table(&mut buf, &counter, &CountsFormat::default()).unwrap();
+        assert_eq!(
+            buf,
+            b"# counted_reads: 10\nseq\treads\nGGG\t5\nAAA\t2\nCCC\t2\nTTT\t1\n"
+        );
+
+        let format = CountsFormat {
+            min_count: 2,
+            top: Some(2),
+            rpm: true,
+        };
+        let mut buf = Vec::new();
+        write_counts_table(&mut buf, &counter, &format).unwrap();
+        assert_eq!(
+            buf,
+            &b"# counted_reads: 10\nseq\treads\trpm\nGGG\t5\t500000.00\nAAA\t2\t200000.00\n"[..]
+        );
+    }
 }
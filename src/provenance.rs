@@ -0,0 +1,225 @@
+//! Provenance of a run: tool version, command line, timing, inputs and the effective config
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use niffler::compression::Format;
+use serde::Serialize;
+
+use crate::config::{FilterConf, FilterParams};
+use crate::input::{DigestedInput, DigestedReads, InputDigest};
+
+/// UTC timestamp like `2024-05-01T12:00:00Z`
+fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = ((secs / 86400) as i64, secs % 86400);
+    // Civil date from the days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// Quotes arguments for a shell if needed, so the command line can be pasted again
+fn shell_quote(arg: &str) -> String {
+    let plain = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:+@%".contains(c));
+    if plain {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+fn compression_name(format: Format) -> &'static str {
+    match format {
+        Format::Gzip => "gzip",
+        Format::Bzip => "bzip2",
+        Format::Lzma => "xz",
+        Format::Zstd => "zstd",
+        Format::No => "none",
+    }
+}
+
+/// Input of a run whose checksum is only known after it was read
+pub struct InputSource {
+    path: String,
+    format: String,
+    compression: Format,
+    has_quality: bool,
+    digest: Rc<RefCell<InputDigest>>,
+}
+
+impl InputSource {
+    pub fn new(path: &str, input: &DigestedReads) -> Self {
+        InputSource {
+            path: path.to_string(),
+            format: input.format.to_string(),
+            compression: input.compression,
+            has_quality: input.has_quality,
+            digest: Rc::clone(&input.digest),
+        }
+    }
+
+    /// Counts table read by `merge` or `enrich`
+    pub fn counts_table(path: &str, input: &DigestedInput) -> Self {
+        InputSource {
+            path: path.to_string(),
+            format: "counts table".to_string(),
+            compression: input.compression,
+            has_quality: false,
+            digest: Rc::clone(&input.digest),
+        }
+    }
+
+    /// Whether the reads carry qualities, see `DigestedReads`
+    pub fn has_quality(&self) -> bool {
+        self.has_quality
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct InputProvenance {
+    pub path: String,
+    pub format: String,
+    pub compression: &'static str,
+    /// Size of the raw input as read
+    pub bytes: u64,
+    /// Missing if the input was not read to its end
+    pub sha256: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Provenance {
+    pub tool: &'static str,
+    pub version: &'static str,
+    pub command_line: Vec<String>,
+    pub started: String,
+    /// End of the filtering, before the outputs are written
+    pub finished: String,
+    pub duration_seconds: f64,
+    pub inputs: Vec<InputProvenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<FilterConf>,
+    /// Files written by the run, listed by the provenance sidecar
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+}
+
+/// Start of a run, turned into its `Provenance` after the inputs are read
+pub struct Run {
+    started: SystemTime,
+    command_line: Vec<String>,
+}
+
+impl Run {
+    pub fn start() -> Self {
+        Run {
+            started: SystemTime::now(),
+            command_line: std::env::args().collect(),
+        }
+    }
+
+    pub fn finish(&self, inputs: &[InputSource], filter: Option<&FilterParams>) -> Provenance {
+        let finished = SystemTime::now();
+        let inputs = inputs
+            .iter()
+            .map(|input| {
+                let digest = input.digest.borrow();
+                InputProvenance {
+                    path: input.path.clone(),
+                    format: input.format.clone(),
+                    compression: compression_name(input.compression),
+                    bytes: digest.bytes(),
+                    sha256: digest.sha256(),
+                }
+            })
+            .collect();
+        Provenance {
+            tool: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            command_line: self.command_line.clone(),
+            started: utc_timestamp(self.started),
+            finished: utc_timestamp(finished),
+            duration_seconds: finished
+                .duration_since(self.started)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            inputs,
+            config: filter.map(FilterConf::from),
+            outputs: Vec::new(),
+        }
+    }
+}
+
+impl Provenance {
+    /// `#` header lines preceding the config and stats of the tab-separated outputs
+    pub fn write_header<T: io::Write>(&self, buf: &mut T) -> io::Result<()> {
+        let command_line: Vec<_> = self.command_line.iter().map(|a| shell_quote(a)).collect();
+        writeln!(buf, "# tool: {} {}", self.tool, self.version)?;
+        writeln!(buf, "# command_line: {}", command_line.join(" "))?;
+        writeln!(buf, "# started: {}", self.started)?;
+        writeln!(buf, "# finished: {}", self.finished)?;
+        writeln!(buf, "# duration_seconds: {:.3}", self.duration_seconds)?;
+        for input in &self.inputs {
+            writeln!(
+                buf,
+                "# input_file: {}\tformat={}\tcompression={}\tbytes={}\tsha256={}",
+                input.path,
+                input.format,
+                input.compression,
+                input.bytes,
+                input.sha256.as_deref().unwrap_or("NA")
+            )?;
+        }
+        if let Some(config) = &self.config {
+            writeln!(buf, "# config: {}", serde_json::to_string(config)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_utc_timestamp() {
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(utc_timestamp(time), "2023-11-14T22:13:20Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(utc_timestamp(leap_day), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("--set=qual_mean"), "--set=qual_mean");
+        assert_eq!(shell_quote("my file.fq"), "'my file.fq'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        // Brace expansion would turn these into several words
+        assert_eq!(shell_quote("{a,b}"), "'{a,b}'");
+        assert_eq!(
+            shell_quote("{sample}.{kind}.{ext}"),
+            "'{sample}.{kind}.{ext}'"
+        );
+    }
+}
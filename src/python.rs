@@ -0,0 +1,326 @@
+//! Python bindings to the matching and counting core
+//!
+//! Built with the `python` feature, see `pyproject.toml` to package it as a wheel with maturin.
+use std::collections::HashMap;
+use std::io;
+
+use bio::io::fastq;
+use counter::Counter;
+use ndarray::Array2;
+use numpy::{IntoPyArray, PyArray1};
+use pyo3::exceptions::{PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+
+use crate::config::{load_config, FilterBuilder, FilterParams};
+use crate::input::{open_reads, Reads};
+use crate::match_type::{CandidateMatch, SearchMatch};
+use crate::matching::{self, PrecomputedPatterns, ReadFilter};
+use crate::sink::MatchSink;
+use crate::stat::{QualStats, RunningStats};
+
+/// Extracted insert as `(insert, quality, reverse_strand, start_pos)` in the orientation of the pattern
+type PyMatch = (String, String, bool, u32);
+
+fn to_py_match(mat: CandidateMatch) -> PyMatch {
+    let mat = mat.materialize();
+    (
+        String::from_utf8_lossy(&mat.seq).into_owned(),
+        String::from_utf8_lossy(&mat.quality).into_owned(),
+        mat.reverse_strand,
+        mat.start_pos,
+    )
+}
+
+/// Columns of the per read report, the quality matrix is stored row-major
+#[derive(Default)]
+struct ReadReportColumns {
+    seq: Vec<String>,
+    start_pos: Vec<u32>,
+    reversed: Vec<bool>,
+    peak_qual: Vec<u8>,
+    mean_qual: Vec<f32>,
+    quality: Vec<u8>,
+}
+
+impl MatchSink for ReadReportColumns {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        self.seq.push(String::from_utf8_lossy(&m.seq).into_owned());
+        self.start_pos.push(m.start_pos);
+        self.reversed.push(m.reverse_strand);
+        self.peak_qual.push(m.peak_qual());
+        self.mean_qual.push(m.accurate_mean_qual());
+        self.quality.extend(m.quality.iter().map(|q| q - 33));
+        Ok(())
+    }
+}
+
+impl ReadReportColumns {
+    fn into_py_dict(self, py: Python<'_>, seq_len: usize) -> PyResult<Bound<'_, PyDict>> {
+        let n_reads = self.seq.len();
+        let quality = Array2::from_shape_vec((n_reads, seq_len), self.quality)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        let dict = PyDict::new(py);
+        dict.set_item("read", self.seq)?;
+        dict.set_item("dist_start", PyArray1::from_vec(py, self.start_pos))?;
+        dict.set_item("reversed", PyArray1::from_vec(py, self.reversed))?;
+        dict.set_item("peak_qual", PyArray1::from_vec(py, self.peak_qual))?;
+        dict.set_item("mean_qual", PyArray1::from_vec(py, self.mean_qual))?;
+        dict.set_item("qual", quality.into_pyarray(py))?;
+        Ok(dict)
+    }
+}
+
+/// Raises like `refuse_quality_features` of the command line tool for input without qualities
+fn refuse_quality_features(params: &FilterParams, outputs: &[&str], reads: &Reads) -> PyResult<()> {
+    if reads.has_quality {
+        return Ok(());
+    }
+    let mut requested = Vec::new();
+    if params.min_peak_qual.is_some() {
+        requested.push("qual_peak");
+    }
+    if params.min_mean_qual.is_some() {
+        requested.push("qual_mean");
+    }
+    requested.extend(outputs);
+    if requested.is_empty() {
+        return Ok(());
+    }
+    Err(PyValueError::new_err(format!(
+        "{} input has no quality information, remove: {}",
+        reads.format,
+        requested.join(", ")
+    )))
+}
+
+fn stats_to_py_dict<'py>(py: Python<'py>, stats: &RunningStats) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("raw_total_reads", stats.total_reads)?;
+    dict.set_item("matching_reads", stats.matching_reads)?;
+    dict.set_item("peak_qual_rejected_reads", stats.peak_rejected)?;
+    dict.set_item("mean_qual_rejected_reads", stats.mean_rejected)?;
+    dict.set_item("ambiguous_matches_rejected", stats.ambigiuous_rejected)?;
+    Ok(dict)
+}
+
+fn qual_stats_to_py_dict<'py>(
+    py: Python<'py>,
+    qual_stats: &QualStats,
+    seq_len: usize,
+) -> PyResult<Bound<'py, PyDict>> {
+    let mut dist_start = Vec::new();
+    let mut peak_qual = Vec::new();
+    let mut mean_qual = Vec::new();
+    let mut reads = Vec::new();
+    let mut reverse_reads = Vec::new();
+    let mut qual: Vec<f32> = Vec::new();
+    for (k, v) in qual_stats.entries_ordered() {
+        dist_start.push(k.0);
+        peak_qual.push(k.1);
+        mean_qual.push(k.2);
+        reads.push(v.reads());
+        reverse_reads.push(v.reverse_reads());
+        qual.extend(v.normalized_qual().iter());
+    }
+    let qual = Array2::from_shape_vec((reads.len(), seq_len), qual)
+        .map_err(|e| PyValueError::new_err(e.to_string()))?;
+    let dict = PyDict::new(py);
+    dict.set_item("dist_start", PyArray1::from_vec(py, dist_start))?;
+    dict.set_item("peak_qual", PyArray1::from_vec(py, peak_qual))?;
+    dict.set_item("mean_qual", PyArray1::from_vec(py, mean_qual))?;
+    dict.set_item("reads", PyArray1::from_vec(py, reads))?;
+    dict.set_item("reverse_reads", PyArray1::from_vec(py, reverse_reads))?;
+    dict.set_item("qual", qual.into_pyarray(py))?;
+    Ok(dict)
+}
+
+fn counts_to_py_dict<'py>(
+    py: Python<'py>,
+    counter: &Counter<Vec<u8>>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let counts: HashMap<String, usize> = counter
+        .iter()
+        .map(|(seq, &count)| (String::from_utf8_lossy(seq).into_owned(), count))
+        .collect();
+    counts.into_pyobject(py)
+}
+
+/// Extracts and counts the inserts between two flanks
+#[pyclass(name = "ReadFilter", module = "read_filter", frozen)]
+pub struct PyReadFilter {
+    params: FilterParams,
+    pats: PrecomputedPatterns,
+}
+
+impl PyReadFilter {
+    fn from_params(params: FilterParams) -> Self {
+        let pats = PrecomputedPatterns::new(&params);
+        PyReadFilter { params, pats }
+    }
+}
+
+#[pymethods]
+impl PyReadFilter {
+    #[new]
+    #[pyo3(signature = (left_flank, right_flank, content_length, expect_begin=None, tolerance=None, qual_peak=None, qual_mean=None))]
+    fn new(
+        left_flank: String,
+        right_flank: String,
+        content_length: u32,
+        expect_begin: Option<u32>,
+        tolerance: Option<u32>,
+        qual_peak: Option<u8>,
+        qual_mean: Option<u8>,
+    ) -> PyResult<Self> {
+        let mut builder = FilterBuilder::new(left_flank, right_flank, content_length);
+        match (expect_begin, tolerance) {
+            (Some(expect_begin), Some(tolerance)) => {
+                builder = builder.window(expect_begin, tolerance)
+            }
+            (None, None) => {}
+            _ => {
+                return Err(PyValueError::new_err(
+                    "expect_begin and tolerance have to be given together",
+                ))
+            }
+        }
+        if let Some(min) = qual_peak {
+            builder = builder.min_peak_qual(min);
+        }
+        if let Some(min) = qual_mean {
+            builder = builder.min_mean_qual(min);
+        }
+        let params = builder
+            .build()
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(Self::from_params(params))
+    }
+
+    /// Load the filter from a read_filter config, TOML and YAML are detected by the extension
+    #[staticmethod]
+    fn from_json(path: &str) -> PyResult<Self> {
+        let params = load_config(path, &[])
+            .and_then(FilterParams::from_conf)
+            .map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(Self::from_params(params))
+    }
+
+    /// Match a single read on both strands, returns a `(forward, reverse)` pair of optional matches
+    fn match_read(&self, seq: &str, qual: &str) -> PyResult<(Option<PyMatch>, Option<PyMatch>)> {
+        match_both_strands(self, seq, qual)
+    }
+
+    /// Count the inserts of a (compressed) FASTQ or FASTA file
+    ///
+    /// Returns a `(counts, stats)` pair of dicts.
+    /// Raises `ValueError` for input without qualities, e.g. FASTA, if the filter has quality thresholds.
+    fn count<'py>(
+        &self,
+        py: Python<'py>,
+        path: &str,
+    ) -> PyResult<(Bound<'py, PyDict>, Bound<'py, PyDict>)> {
+        let (counter, stats) = py.detach(|| -> PyResult<_> {
+            let reads = open_reads(path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+            refuse_quality_features(&self.params, &[], &reads)?;
+            let mut stats = RunningStats::default();
+            let mut counter = Counter::new();
+            ReadFilter::new(reads.records, &self.params, &mut stats).count_into(&mut counter);
+            reads
+                .error
+                .check()
+                .map_err(|e| PyIOError::new_err(e.to_string()))?;
+            Ok((counter, stats))
+        })?;
+        Ok((
+            counts_to_py_dict(py, &counter)?,
+            stats_to_py_dict(py, &stats)?,
+        ))
+    }
+
+    /// Process a (compressed) FASTQ or FASTA file like the command line tool
+    ///
+    /// The returned dict holds `counts` and `stats` and, if requested, the `read_report` and
+    /// the `qc_report` as dicts of NumPy arrays.
+    /// Raises `ValueError` for input without qualities, e.g. FASTA, with quality thresholds or reports.
+    #[pyo3(signature = (path, read_report=false, qc_report=false))]
+    fn process<'py>(
+        &self,
+        py: Python<'py>,
+        path: &str,
+        read_report: bool,
+        qc_report: bool,
+    ) -> PyResult<Bound<'py, PyDict>> {
+        let (counter, stats, qual_stats, columns) = py.detach(|| -> PyResult<_> {
+            let reads = open_reads(path).map_err(|e| PyIOError::new_err(e.to_string()))?;
+            let mut quality_outputs = Vec::new();
+            if read_report {
+                quality_outputs.push("read_report");
+            }
+            if qc_report {
+                quality_outputs.push("qc_report");
+            }
+            refuse_quality_features(&self.params, &quality_outputs, &reads)?;
+            let mut stats = RunningStats::default();
+            let mut counter = Counter::new();
+            let mut qual_stats = QualStats::new();
+            let mut columns = ReadReportColumns::default();
+            let mut sinks: Vec<&mut dyn MatchSink> = vec![&mut counter];
+            if qc_report {
+                sinks.push(&mut qual_stats);
+            }
+            if read_report {
+                sinks.push(&mut columns);
+            }
+            let error = reads.error;
+            ReadFilter::new(reads.records, &self.params, &mut stats)
+                .run(&mut sinks)
+                .and_then(|_| error.check())
+                .map_err(|e| PyIOError::new_err(e.to_string()))?;
+            Ok((counter, stats, qual_stats, columns))
+        })?;
+        let seq_len = self.params.insert_length as usize;
+        let dict = PyDict::new(py);
+        dict.set_item("counts", counts_to_py_dict(py, &counter)?)?;
+        dict.set_item("stats", stats_to_py_dict(py, &stats)?)?;
+        if read_report {
+            dict.set_item("read_report", columns.into_py_dict(py, seq_len)?)?;
+        }
+        if qc_report {
+            dict.set_item(
+                "qc_report",
+                qual_stats_to_py_dict(py, &qual_stats, seq_len)?,
+            )?;
+        }
+        Ok(dict)
+    }
+
+    #[getter]
+    fn content_length(&self) -> u32 {
+        self.params.insert_length
+    }
+}
+
+/// Match a single read on both strands without applying the quality filters
+#[pyfunction]
+fn match_both_strands(
+    read_filter: &PyReadFilter,
+    seq: &str,
+    qual: &str,
+) -> PyResult<(Option<PyMatch>, Option<PyMatch>)> {
+    if seq.len() != qual.len() {
+        return Err(PyValueError::new_err(
+            "Sequence and quality need to be of equal length",
+        ));
+    }
+    let rec = fastq::Record::with_attrs("", None, seq.as_bytes(), qual.as_bytes());
+    let (fwd, rev) = matching::match_both_strands(&rec, &read_filter.pats);
+    Ok((fwd.map(to_py_match), rev.map(to_py_match)))
+}
+
+#[pymodule]
+fn read_filter(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyReadFilter>()?;
+    m.add_function(wrap_pyfunction!(match_both_strands, m)?)?;
+    Ok(())
+}
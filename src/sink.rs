@@ -0,0 +1,311 @@
+//! Consumers of accepted matches from which the outputs of a run are composed
+use std::borrow::Cow;
+use std::io::{self, Write};
+
+use counter::Counter;
+use csv::{Writer, WriterBuilder};
+use serde::Serialize;
+
+use crate::match_type::SearchMatch;
+use crate::output::write_read_report_header;
+use crate::stat::{count_seq, QualStats};
+
+/// Receiver of every `SearchMatch` accepted by a `ReadFilter`
+///
+/// Implement this to plug custom outputs into a run, see `ReadFilter::run`.
+/// Several sinks can be combined by collecting them into a `Vec`.
+pub trait MatchSink {
+    /// Handle a single match
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()>;
+    /// Handle a single match along with the id of its read, only sinks keeping the id need to override it
+    fn consume_with_id(&mut self, _id: &str, m: &SearchMatch) -> io::Result<()> {
+        self.consume(m)
+    }
+    /// Called once after the input is exhausted, e.g. to flush buffered output
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: MatchSink + ?Sized> MatchSink for &mut S {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        (**self).consume(m)
+    }
+    fn consume_with_id(&mut self, id: &str, m: &SearchMatch) -> io::Result<()> {
+        (**self).consume_with_id(id, m)
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<S: MatchSink + ?Sized> MatchSink for Box<S> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        (**self).consume(m)
+    }
+    fn consume_with_id(&mut self, id: &str, m: &SearchMatch) -> io::Result<()> {
+        (**self).consume_with_id(id, m)
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+/// Passes every match on to all contained sinks in order
+impl<S: MatchSink> MatchSink for Vec<S> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        for sink in self.iter_mut() {
+            sink.consume(m)?;
+        }
+        Ok(())
+    }
+    fn consume_with_id(&mut self, id: &str, m: &SearchMatch) -> io::Result<()> {
+        for sink in self.iter_mut() {
+            sink.consume_with_id(id, m)?;
+        }
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        for sink in self.iter_mut() {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the extracted sequences
+impl MatchSink for Counter<Vec<u8>> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        count_seq(self, &m.seq);
+        Ok(())
+    }
+}
+
+/// Collects the QC information, write it with `QualStats::write_to_buf` afterwards
+impl MatchSink for QualStats {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        self.append(m);
+        Ok(())
+    }
+}
+
+/// Writes the tab-separated per read report, see `SearchMatch::write_read_report_line`
+pub struct ReadReportSink<W: io::Write> {
+    wtr: Writer<W>,
+}
+
+impl<W: io::Write> ReadReportSink<W> {
+    /// Writes the header for inserts of `seq_len` immediately
+    pub fn new(mut buf: W, seq_len: usize) -> io::Result<Self> {
+        write_read_report_header(&mut buf, seq_len)?;
+        let wtr = WriterBuilder::new()
+            .has_headers(false)
+            .delimiter(b'\t')
+            .from_writer(buf);
+        Ok(ReadReportSink { wtr })
+    }
+
+    /// Flushes the report and returns the writer, e.g. to finish a compressed stream
+    pub fn into_inner(self) -> io::Result<W> {
+        self.wtr
+            .into_inner()
+            .map_err(|e| io::Error::new(e.error().kind(), e.error().to_string()))
+    }
+}
+
+impl<W: io::Write> MatchSink for ReadReportSink<W> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        m.write_read_report_line(&mut self.wtr)?;
+        Ok(())
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.wtr.flush()
+    }
+}
+
+/// Line of the JSON Lines read report, with the columns of the tab-separated one
+#[derive(Serialize)]
+struct ReadReportLine<'a> {
+    read: Cow<'a, str>,
+    dist_start: u32,
+    reversed: bool,
+    peak_qual: u8,
+    mean_qual: f32,
+    qual: Vec<u8>,
+}
+
+/// Writes the per read report as JSON Lines, the qualities of a read are an array
+pub struct JsonlReadReportSink<W: io::Write> {
+    buf: W,
+}
+
+impl<W: io::Write> JsonlReadReportSink<W> {
+    pub fn new(buf: W) -> Self {
+        JsonlReadReportSink { buf }
+    }
+
+    /// Returns the writer, e.g. to finish a compressed stream
+    pub fn into_inner(self) -> W {
+        self.buf
+    }
+}
+
+impl<W: io::Write> MatchSink for JsonlReadReportSink<W> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        let line = ReadReportLine {
+            read: String::from_utf8_lossy(&m.seq),
+            dist_start: m.start_pos,
+            reversed: m.reverse_strand,
+            peak_qual: m.peak_qual(),
+            mean_qual: m.accurate_mean_qual(),
+            qual: m.quality.iter().map(|v| v - 33).collect(),
+        };
+        serde_json::to_writer(&mut self.buf, &line)?;
+        writeln!(self.buf)
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.buf.flush()
+    }
+}
+
+/// Exports the matches as FASTQ records in the orientation defined by the pattern
+///
+/// Records keep the id of their read, the description holds the strand.
+/// Matches passed without it by `consume` get an empty id.
+pub struct FastqSink<W: io::Write> {
+    buf: io::BufWriter<W>,
+}
+
+impl<W: io::Write> FastqSink<W> {
+    pub fn new(buf: W) -> Self {
+        FastqSink {
+            buf: io::BufWriter::new(buf),
+        }
+    }
+
+    /// Flushes the records and returns the writer, e.g. to finish a compressed stream
+    pub fn into_inner(self) -> io::Result<W> {
+        self.buf.into_inner().map_err(|e| e.into_error())
+    }
+}
+
+impl<W: io::Write> MatchSink for FastqSink<W> {
+    fn consume(&mut self, m: &SearchMatch) -> io::Result<()> {
+        self.consume_with_id("", m)
+    }
+    fn consume_with_id(&mut self, id: &str, m: &SearchMatch) -> io::Result<()> {
+        let strand = if m.reverse_strand { "-" } else { "+" };
+        writeln!(self.buf, "@{} {}", id, strand)?;
+        self.buf.write_all(&m.seq)?;
+        self.buf.write_all(b"\n+\n")?;
+        self.buf.write_all(&m.quality)?;
+        self.buf.write_all(b"\n")
+    }
+    fn finish(&mut self) -> io::Result<()> {
+        self.buf.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FilterBuilder;
+    use crate::matching::ReadFilter;
+    use crate::stat::RunningStats;
+    use bio::io::fastq;
+
+    const READS: &[u8] = b"@fwd_read\nAGGGCCAGTTTTTGCCCAGGC\n+\nIIIIIIIIIIIIIIIIIIIII\n\
+@rev_read\nGCCTGGGCAAAACCTGGCCCT\n+\nIIIIIIIIIIII5IIIIIIII\n\
+@no_flanks\nACGTACGTACGTACGTACGTA\n+\nIIIIIIIIIIIIIIIIIIIII\n";
+
+    /// Feeds the `READS` through a filter for the 5 nt insert between the flanks into `sink`
+    fn run_filter<S: MatchSink>(sink: &mut S) {
+        let cfg = FilterBuilder::new("AGGGCCAG", "GCCCAGGC", 5)
+            .build()
+            .unwrap();
+        let mut stats = RunningStats::default();
+        let records = fastq::Reader::new(READS).records();
+        ReadFilter::new(records, &cfg, &mut stats)
+            .run(sink)
+            .unwrap();
+        assert_eq!(stats.total_reads, 3);
+    }
+
+    #[test]
+    fn test_combined_sinks() {
+        let mut counter: Counter<Vec<u8>> = Counter::new();
+        let mut qual_stats = QualStats::new();
+        run_filter(&mut vec![
+            &mut counter as &mut dyn MatchSink,
+            &mut qual_stats as &mut dyn MatchSink,
+        ]);
+        assert_eq!(counter.len(), 2);
+        assert_eq!(counter[&b"TTTTT".to_vec()], 1);
+        assert_eq!(counter[&b"GTTTT".to_vec()], 1);
+        for reverse in [false, true] {
+            assert_eq!(qual_stats.start_positions(reverse).values().sum::<u32>(), 1);
+        }
+    }
+
+    #[test]
+    fn test_read_report_round_trip() {
+        let mut buf = Vec::new();
+        run_filter(&mut ReadReportSink::new(&mut buf, 5).unwrap());
+        let report = String::from_utf8(buf).unwrap();
+        let rows: Vec<Vec<&str>> = report
+            .lines()
+            .filter(|l| !l.starts_with('#'))
+            .map(|l| l.split('\t').collect())
+            .collect();
+        assert_eq!(rows.len(), 3);
+        assert_eq!(rows[0].len(), 5 + 5);
+        assert_eq!(rows[1][..5], ["TTTTT", "8", "false", "40", "40.0"]);
+        assert_eq!(rows[2][..5], ["GTTTT", "8", "true", "20", "36.0"]);
+        assert_eq!(rows[2][5..], ["20", "40", "40", "40", "40"]);
+    }
+
+    #[test]
+    fn test_jsonl_read_report_round_trip() {
+        let mut buf = Vec::new();
+        run_filter(&mut JsonlReadReportSink::new(&mut buf));
+        let lines: Vec<serde_json::Value> = buf
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1]["read"], "GTTTT");
+        assert_eq!(lines[1]["reversed"], true);
+        assert_eq!(lines[1]["qual"], serde_json::json!([20, 40, 40, 40, 40]));
+
+        // Invalid UTF-8 in the input is replaced instead of panicking
+        let mut buf = Vec::new();
+        let m = SearchMatch {
+            seq: b"AC\xffGT".to_vec(),
+            quality: b"IIIII".to_vec(),
+            reverse_strand: false,
+            start_pos: 0,
+        };
+        JsonlReadReportSink::new(&mut buf).consume(&m).unwrap();
+        let line: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(line["read"], "AC\u{fffd}GT");
+    }
+
+    #[test]
+    fn test_fastq_round_trip() {
+        let mut buf = Vec::new();
+        run_filter(&mut FastqSink::new(&mut buf));
+        let records: Vec<_> = fastq::Reader::new(&buf[..])
+            .records()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].id(), "fwd_read");
+        assert_eq!(records[0].desc(), Some("+"));
+        assert_eq!(records[0].seq(), b"TTTTT");
+        assert_eq!(records[1].id(), "rev_read");
+        assert_eq!(records[1].desc(), Some("-"));
+        assert_eq!(records[1].seq(), b"GTTTT");
+        assert_eq!(records[1].qual(), b"5IIII");
+    }
+}
@@ -1,11 +1,17 @@
 //! Types to track information about number of matches and quality information
 use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::str::FromStr;
 use std::{collections::HashMap, io};
 
+use counter::Counter;
 use ndarray::Array1;
 
 use crate::match_type;
-use crate::output::write_qual_report_header;
+use crate::matching::PrecomputedPatterns;
+use crate::output::{write_binned_qual_report_header, write_qual_report_header};
 /// General information collected during read filtering
 ///
 /// Usage: mutably borrowed by `read_filter::ReadFilter`
@@ -18,19 +24,182 @@ pub struct RunningStats {
     pub mean_rejected: u32,
 }
 
+impl std::ops::AddAssign<&RunningStats> for RunningStats {
+    fn add_assign(&mut self, rhs: &RunningStats) {
+        self.total_reads += rhs.total_reads;
+        self.matching_reads += rhs.matching_reads;
+        self.ambigiuous_rejected += rhs.ambigiuous_rejected;
+        self.peak_rejected += rhs.peak_rejected;
+        self.mean_rejected += rhs.mean_rejected;
+    }
+}
+
+/// Increment the count of `seq` by looking it up as a borrowed slice
+///
+/// In contrast to `Counter::update` the key is only copied to the heap if it was not observed before.
+pub fn count_seq(counter: &mut Counter<Vec<u8>>, seq: &[u8]) {
+    match counter.get_mut(seq) {
+        Some(count) => *count += 1,
+        None => {
+            counter.insert(seq.to_vec(), 1);
+        }
+    }
+}
+
+/// Whole-read QC of every record seen by a `ReadFilter`, matching or not
+///
+/// Collected with `ReadFilter::with_read_stats` to diagnose e.g. phasing or primer dimers.
+#[derive(Default, Debug)]
+pub struct ReadStats {
+    pub reads: u32,
+    lengths: BTreeMap<usize, u32>,
+    cycle_qual_sum: Vec<u64>,
+    cycle_reads: Vec<u32>,
+    /// Reads by their rounded GC percentage
+    gc_percent: BTreeMap<u8, u32>,
+    gc_bases: u64,
+    called_bases: u64,
+    /// Reads by strand and start of the left flank, in the coordinates of the positional window
+    flank_pos: BTreeMap<(bool, usize), u32>,
+    without_flank: u32,
+}
+
+impl ReadStats {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn append(&mut self, seq: &[u8], qual: &[u8], patterns: &PrecomputedPatterns) {
+        self.reads += 1;
+        *self.lengths.entry(seq.len()).or_insert(0) += 1;
+        if self.cycle_reads.len() < qual.len() {
+            self.cycle_qual_sum.resize(qual.len(), 0);
+            self.cycle_reads.resize(qual.len(), 0);
+        }
+        for (cycle, &q) in qual.iter().enumerate() {
+            self.cycle_qual_sum[cycle] += q.saturating_sub(33) as u64;
+            self.cycle_reads[cycle] += 1;
+        }
+        let gc = seq.iter().filter(|b| matches!(b, b'G' | b'C')).count();
+        let called = seq
+            .iter()
+            .filter(|b| matches!(b, b'A' | b'C' | b'G' | b'T'))
+            .count();
+        self.gc_bases += gc as u64;
+        self.called_bases += called as u64;
+        if called > 0 {
+            let percent = (100. * gc as f64 / called as f64).round() as u8;
+            *self.gc_percent.entry(percent).or_insert(0) += 1;
+        }
+
+        // Every hit counts, also outside of the window
+        let mut found = false;
+        for idx in patterns.fwd_start.find_all(seq) {
+            *self.flank_pos.entry((false, idx)).or_insert(0) += 1;
+            found = true;
+        }
+        for (idx, window) in seq.windows(patterns.start_len).enumerate() {
+            if window == &patterns.rev_start[..] {
+                let pos = seq.len() - (idx + patterns.start_len);
+                *self.flank_pos.entry((true, pos)).or_insert(0) += 1;
+                found = true;
+            }
+        }
+        if !found {
+            self.without_flank += 1;
+        }
+    }
+
+    /// Writes the distributions as `metric\tbin\tvalue` rows after a few summary header lines
+    pub fn write_to_buf<T: io::Write>(
+        &self,
+        buf: &mut T,
+        window: Option<(usize, usize)>,
+    ) -> io::Result<()> {
+        writeln!(buf, "# reads: {}", self.reads)?;
+        writeln!(
+            buf,
+            "# gc_content: {:.4}",
+            self.gc_bases as f64 / self.called_bases.max(1) as f64
+        )?;
+        if let Some((expt_begin, expt_end)) = window {
+            writeln!(buf, "# left_flank_window: {}-{}", expt_begin, expt_end)?;
+        }
+        writeln!(buf, "# reads_without_left_flank: {}", self.without_flank)?;
+        writeln!(buf, "metric\tbin\tvalue")?;
+        for (length, reads) in &self.lengths {
+            writeln!(buf, "read_length\t{}\t{}", length, reads)?;
+        }
+        for (cycle, (sum, reads)) in self
+            .cycle_qual_sum
+            .iter()
+            .zip(&self.cycle_reads)
+            .enumerate()
+        {
+            writeln!(
+                buf,
+                "cycle_mean_qual\t{}\t{:.2}",
+                cycle,
+                *sum as f64 / *reads as f64
+            )?;
+        }
+        for (percent, reads) in &self.gc_percent {
+            writeln!(buf, "gc_percent\t{}\t{}", percent, reads)?;
+        }
+        for ((reverse, pos), reads) in &self.flank_pos {
+            let metric = if *reverse {
+                "left_flank_pos_rev"
+            } else {
+                "left_flank_pos_fwd"
+            };
+            writeln!(buf, "{}\t{}\t{}", metric, pos, reads)?;
+        }
+        Ok(())
+    }
+}
+
 /// Tracker of important QC information
 ///
 /// How are quality scores distributed accross the region of interest?
 /// How accurate is the position information?
 /// Append the records to this map.
+#[derive(Default)]
 pub struct QualStats {
     dat: HashMap<(u32, u8, u8), QualStatEntry>,
+    /// Reads by PHRED score for each position of the insert
+    pos_hist: Vec<[u32; PHRED_BINS]>,
+}
+
+/// PHRED scores up to 93 are printable in the FASTQ encoding
+const PHRED_BINS: usize = 94;
+
+/// Percentiles of the per position quality distribution, the 10th and 90th mark the whiskers of a box plot
+const PERCENTILES: [(&str, f64); 5] = [
+    ("p10", 0.1),
+    ("q1", 0.25),
+    ("median", 0.5),
+    ("q3", 0.75),
+    ("p90", 0.9),
+];
+
+/// Nearest rank percentile of a histogram holding `total` observations
+fn hist_percentile(hist: &[u32], total: u32, fraction: f64) -> usize {
+    let rank = ((fraction * total as f64).ceil() as u32).max(1);
+    let mut seen = 0;
+    for (qual, &count) in hist.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return qual;
+        }
+    }
+    hist.len() - 1
 }
 
 impl QualStats {
     pub fn new() -> Self {
         QualStats {
             dat: HashMap::new(),
+            pos_hist: Vec::new(),
         }
     }
 
@@ -40,8 +209,83 @@ impl QualStats {
             .entry(key)
             .and_modify(|existing| *existing += mat.into())
             .or_insert_with(|| mat.into());
+        if self.pos_hist.len() < mat.quality.len() {
+            self.pos_hist.resize(mat.quality.len(), [0; PHRED_BINS]);
+        }
+        for (hist, &q) in self.pos_hist.iter_mut().zip(&mat.quality) {
+            hist[(q.saturating_sub(33) as usize).min(PHRED_BINS - 1)] += 1;
+        }
     }
-    fn entries_ordered(&self) -> QualStatsIter {
+
+    /// 10th, 25th, 50th, 75th and 90th percentile of the qualities at each position of the insert
+    pub fn position_percentiles(&self) -> Vec<[usize; 5]> {
+        self.pos_hist
+            .iter()
+            .map(|hist| {
+                let total: u32 = hist.iter().sum();
+                let mut values = [0; 5];
+                for (value, (_, fraction)) in values.iter_mut().zip(PERCENTILES.iter()) {
+                    *value = hist_percentile(hist, total, *fraction);
+                }
+                values
+            })
+            .collect()
+    }
+
+    /// Accepted reads of one strand by the start of the insert
+    ///
+    /// Reverse strand starts count from the end of the read, in the reverse complement.
+    pub fn start_positions(&self, reverse: bool) -> BTreeMap<u32, u32> {
+        let mut positions = BTreeMap::new();
+        for (&(start_pos, _, _), entry) in &self.dat {
+            let reads = if reverse {
+                entry.reverse_reads()
+            } else {
+                entry.reads() - entry.reverse_reads()
+            };
+            if reads > 0 {
+                *positions.entry(start_pos).or_insert(0) += reads;
+            }
+        }
+        positions
+    }
+
+    /// Mean quality at each position of the insert over all reads
+    pub fn position_means(&self) -> Vec<f64> {
+        self.pos_hist
+            .iter()
+            .map(|hist| {
+                let total: u32 = hist.iter().sum();
+                let sum: u64 = hist
+                    .iter()
+                    .enumerate()
+                    .map(|(q, &n)| q as u64 * n as u64)
+                    .sum();
+                sum as f64 / total.max(1) as f64
+            })
+            .collect()
+    }
+
+    /// Table with a row per position of the insert, the percentiles of its qualities and the fractions below Q20 and Q30
+    pub fn write_position_table<T: io::Write>(&self, buf: &mut T) -> io::Result<()> {
+        write!(buf, "pos")?;
+        for (name, _) in PERCENTILES.iter() {
+            write!(buf, "\t{}", name)?;
+        }
+        writeln!(buf, "\tbelow_q20\tbelow_q30")?;
+        let percentiles = self.position_percentiles();
+        for (pos, hist) in self.pos_hist.iter().enumerate() {
+            let total: u32 = hist.iter().sum();
+            let below = |q: usize| hist[..q].iter().sum::<u32>() as f64 / total.max(1) as f64;
+            write!(buf, "{}", pos)?;
+            for value in &percentiles[pos] {
+                write!(buf, "\t{}", value)?;
+            }
+            writeln!(buf, "\t{:.4}\t{:.4}", below(20), below(30))?;
+        }
+        Ok(())
+    }
+    pub(crate) fn entries_ordered(&self) -> QualStatsIter<'_> {
         let mut keys: Vec<_> = self.dat.keys().cloned().collect();
         keys.sort_unstable_by_key(|&e| Reverse(e)); // TODO: Verify behavior with tuple key
         QualStatsIter {
@@ -50,8 +294,8 @@ impl QualStats {
         }
     }
     #[allow(dead_code)]
-    fn entries_unordered(&self) -> QualStatsIter {
-        let keys: Vec<_> = self.dat.keys().into_iter().cloned().collect();
+    fn entries_unordered(&self) -> QualStatsIter<'_> {
+        let keys: Vec<_> = self.dat.keys().cloned().collect();
         QualStatsIter {
             ord_keys: keys,
             qs: self,
@@ -76,13 +320,132 @@ impl QualStats {
             for e in v.normalized_qual().iter() {
                 write!(buf, "\t{}", e)?;
             }
-            write!(buf, "\n")?;
+            writeln!(buf)?;
         }
         Ok(())
     }
 }
 
-struct QualStatsIter<'a> {
+/// Lower bin edges of the binned QC report, each bin reaches up to the next edge and the last one is open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QualBins {
+    pub position: Vec<u32>,
+    pub quality: Vec<u8>,
+}
+
+impl Default for QualBins {
+    fn default() -> Self {
+        QualBins {
+            position: (0..=300).step_by(10).collect(),
+            quality: vec![0, 10, 20, 30],
+        }
+    }
+}
+
+impl QualBins {
+    /// Parses comma separated edges, e.g. `0,10,20,30`, starting at 0 and strictly increasing
+    pub fn parse_edges<T>(edges: &str) -> Result<Vec<T>, Box<dyn Error>>
+    where
+        T: FromStr + Ord + Default,
+        T::Err: Display,
+    {
+        let edges = edges
+            .split(',')
+            .map(|e| {
+                e.trim()
+                    .parse()
+                    .map_err(|err| format!("Invalid bin edge {:?}: {}", e, err))
+            })
+            .collect::<Result<Vec<T>, _>>()?;
+        if edges.first() != Some(&T::default()) {
+            return Err("The bin edges have to start at 0".into());
+        }
+        if edges.windows(2).any(|w| w[0] >= w[1]) {
+            return Err("The bin edges have to be strictly increasing".into());
+        }
+        Ok(edges)
+    }
+}
+
+/// Index of the bin of `value`
+fn bin_of<T: Ord>(edges: &[T], value: &T) -> usize {
+    edges.partition_point(|e| e <= value).saturating_sub(1)
+}
+
+/// Label like `10-20` of the half-open bin or `30+` for the last one
+fn bin_label<T: Display>(edges: &[T], bin: usize) -> String {
+    match edges.get(bin + 1) {
+        Some(upper) => format!("{}-{}", edges[bin], upper),
+        None => format!("{}+", edges[bin]),
+    }
+}
+
+impl QualStats {
+    /// Write the QC report with the reads grouped into the bins, preceded by the retention curves
+    ///
+    /// A `# retained_reads` header line per quality threshold gives the reads that would still pass
+    /// it as minimal peak or mean quality, counting only reads accepted by the current filter.
+    pub fn write_binned_to_buf<T: io::Write>(
+        &self,
+        buf: &mut T,
+        seq_len: usize,
+        bins: &QualBins,
+    ) -> io::Result<()> {
+        let max_qual = self.dat.keys().map(|k| k.1.max(k.2)).max().unwrap_or(0);
+        let mut peak_reads = vec![0u32; max_qual as usize + 1];
+        let mut mean_reads = vec![0u32; max_qual as usize + 1];
+        let mut binned: BTreeMap<(usize, usize, usize), QualStatEntry> = BTreeMap::new();
+        for (k, v) in self.entries_ordered() {
+            peak_reads[k.1 as usize] += v.reads();
+            mean_reads[k.2 as usize] += v.reads();
+            let key = (
+                bin_of(&bins.position, &k.0),
+                bin_of(&bins.quality, &k.1),
+                bin_of(&bins.quality, &k.2),
+            );
+            match binned.get_mut(&key) {
+                Some(existing) => *existing += v.clone(),
+                None => {
+                    binned.insert(key, v.clone());
+                }
+            }
+        }
+        let (mut peak_retained, mut mean_retained) = (0, 0);
+        let mut retained = Vec::with_capacity(peak_reads.len());
+        for threshold in (0..peak_reads.len()).rev() {
+            peak_retained += peak_reads[threshold];
+            mean_retained += mean_reads[threshold];
+            retained.push((threshold, peak_retained, mean_retained));
+        }
+        for (threshold, peak, mean) in retained.into_iter().rev() {
+            writeln!(
+                buf,
+                "# retained_reads: min_qual={}\tpeak_qual={}\tmean_qual={}",
+                threshold, peak, mean
+            )?;
+        }
+
+        write_binned_qual_report_header(buf, seq_len)?;
+        for (k, v) in binned {
+            write!(
+                buf,
+                "{dist_start}\t{peak_qual}\t{mean_qual}\t{reads}\t{reverse_reads}",
+                dist_start = bin_label(&bins.position, k.0),
+                peak_qual = bin_label(&bins.quality, k.1),
+                mean_qual = bin_label(&bins.quality, k.2),
+                reads = v.reads(),
+                reverse_reads = v.reverse_reads()
+            )?;
+            for e in v.normalized_qual().iter() {
+                write!(buf, "\t{:.2}", e)?;
+            }
+            writeln!(buf)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct QualStatsIter<'a> {
     ord_keys: Vec<(u32, u8, u8)>,
     qs: &'a QualStats,
 }
@@ -97,21 +460,22 @@ impl<'a> Iterator for QualStatsIter<'a> {
     }
 }
 
-struct QualStatEntry {
+#[derive(Clone)]
+pub(crate) struct QualStatEntry {
     read_count: u32,
     reverse_count: u32,
     qual_arr: Array1<u32>,
 }
 
 impl QualStatEntry {
-    fn normalized_qual(&self) -> Array1<f32> {
+    pub(crate) fn normalized_qual(&self) -> Array1<f32> {
         let arr: Array1<f32> = self.qual_arr.mapv(|i| i as f32);
         arr / (self.read_count as f32) - 33.0
     }
-    fn reads(&self) -> u32 {
+    pub(crate) fn reads(&self) -> u32 {
         self.read_count
     }
-    fn reverse_reads(&self) -> u32 {
+    pub(crate) fn reverse_reads(&self) -> u32 {
         self.reverse_count
     }
 }
@@ -135,3 +499,51 @@ impl std::ops::AddAssign for QualStatEntry {
         self.qual_arr += &rhs.qual_arr; // Would panic if shapes mismatch
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::match_type::SearchMatch;
+
+    fn search_match(start_pos: u32, quality: &[u8]) -> SearchMatch {
+        SearchMatch {
+            seq: b"ACG".to_vec(),
+            quality: quality.iter().map(|q| q + 33).collect(),
+            reverse_strand: false,
+            start_pos,
+        }
+    }
+
+    #[test]
+    fn test_binned_report() {
+        let mut qs = QualStats::new();
+        qs.append(&search_match(3, &[30, 30, 30]));
+        qs.append(&search_match(4, &[35, 35, 35]));
+        qs.append(&search_match(12, &[5, 20, 20]));
+        let bins = QualBins {
+            position: QualBins::parse_edges("0,10").unwrap(),
+            quality: QualBins::parse_edges("0,10,20,30").unwrap(),
+        };
+        let mut buf = Vec::new();
+        qs.write_binned_to_buf(&mut buf, 3, &bins).unwrap();
+        let report = String::from_utf8(buf).unwrap();
+        assert!(report.contains("# retained_reads: min_qual=10\tpeak_qual=2\tmean_qual=3\n"));
+        assert!(report.contains("# retained_reads: min_qual=35\tpeak_qual=1\tmean_qual=1\n"));
+        assert!(report.ends_with("0-10\t30+\t30+\t2\t0\t32.50\t32.50\t32.50\n10+\t0-10\t10-20\t1\t0\t5.00\t20.00\t20.00\n"));
+        assert!(!report.contains("median"));
+
+        let mut buf = Vec::new();
+        qs.write_position_table(&mut buf).unwrap();
+        let table = String::from_utf8(buf).unwrap();
+        let mut lines = table.lines();
+        assert_eq!(
+            lines.next(),
+            Some("pos\tp10\tq1\tmedian\tq3\tp90\tbelow_q20\tbelow_q30")
+        );
+        assert_eq!(lines.next(), Some("0\t5\t5\t30\t35\t35\t0.3333\t0.3333"));
+        assert_eq!(lines.count(), 2);
+
+        assert!(QualBins::parse_edges::<u8>("10,20").is_err());
+        assert!(QualBins::parse_edges::<u8>("0,20,20").is_err());
+    }
+}
@@ -0,0 +1,63 @@
+/* Exercises the C ABI of read_filter, run by tests/capi.rs */
+#include <stdio.h>
+#include <string.h>
+
+#include "read_filter.h"
+
+#define CHECK(cond)                                                   \
+    do {                                                              \
+        if (!(cond)) {                                                \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__,    \
+                    __LINE__, #cond);                                 \
+            return 1;                                                 \
+        }                                                             \
+    } while (0)
+
+static const char *CONFIG =
+    "{\"left_flank\": \"AGAGAGGC\", \"right_flank\": \"GCCCAGGC\","
+    " \"content_length\": 21, \"expect_begin\": 36, \"tolerance\": 4,"
+    " \"qual_peak\": 20}";
+
+/* Reads taken from tests/data/sample.fastq */
+static const char *FWD_READ =
+    "GCTAAAGACAATTACATAACATACACGTCAGCACGAAGAGAGGCACGTACGTACGTACGTACGTAGCCCAGGCAACTTGTTGGCCCAGTGTGAATCGCTT";
+static const char *REV_READ =
+    "ATGCCAGTCCGATGGGGTGGACACAGCGCCTGGGCTACGTACGTACGTACGTACGTGCCTCTCTAAGTAAAGGCGTATGCATCACACTTACTTAACCCTT";
+
+int main(void) {
+    char qual[128];
+    size_t len = strlen(FWD_READ);
+    memset(qual, 'I', len);
+    qual[len] = '\0';
+
+    CHECK(rf_filter_from_json("{\"left_flank\": 1}") == NULL);
+    CHECK(rf_last_error() != NULL);
+
+    RfFilter *filter = rf_filter_from_json(CONFIG);
+    CHECK(filter != NULL);
+    CHECK(rf_insert_length(filter) == 21);
+    RfMatch *mat = rf_match_new(filter);
+    CHECK(mat != NULL);
+
+    CHECK(rf_filter_read(filter, FWD_READ, qual, len, mat) == RF_ACCEPTED);
+    CHECK(strcmp(mat->insert, "ACGTACGTACGTACGTACGTA") == 0);
+    CHECK(!mat->reverse_strand);
+    CHECK(mat->start_pos == 44);
+
+    CHECK(rf_filter_read(filter, REV_READ, qual, strlen(REV_READ), mat) == RF_ACCEPTED);
+    CHECK(strcmp(mat->insert, "ACGTACGTACGTACGTACGTA") == 0);
+    CHECK(mat->reverse_strand);
+
+    /* Low quality reads still report the insert */
+    memset(qual, '+', len);
+    CHECK(rf_filter_read(filter, FWD_READ, qual, len, mat) == RF_PEAK_QUAL_REJECTED);
+    CHECK(strcmp(mat->quality, "+++++++++++++++++++++") == 0);
+
+    CHECK(rf_filter_read(filter, FWD_READ, qual, 40, mat) == RF_NO_MATCH);
+    CHECK(rf_filter_read(filter, NULL, qual, len, mat) == RF_INVALID_ARGUMENT);
+
+    rf_match_free(mat);
+    rf_filter_free(filter);
+    printf("ok\n");
+    return 0;
+}
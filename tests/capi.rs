@@ -0,0 +1,56 @@
+//! Compiles and runs the C harness in `tests/c` against the cdylib
+#![cfg(unix)]
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+#[test]
+fn header_up_to_date() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let config = cbindgen::Config::from_file(root.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::generate_with_config(&root, config)
+        .expect("Generating the header failed")
+        .write(&mut generated);
+    let committed = std::fs::read(root.join("include/read_filter.h")).unwrap();
+    assert!(
+        generated == committed,
+        "include/read_filter.h is outdated, regenerate it with `cbindgen --config cbindgen.toml --output include/read_filter.h`"
+    );
+}
+
+#[test]
+fn c_harness() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    // Integration tests are placed in target/<profile>/deps next to the freshly built cdylib
+    let lib_dir = env::current_exe().unwrap().parent().unwrap().to_path_buf();
+    let exe = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("capi_harness");
+    let cc = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+
+    let status = Command::new(cc)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join("tests/c/harness.c"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg("-lread_filter")
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .expect("Failed to invoke the C compiler");
+    assert!(status.success(), "Compiling the C harness failed");
+
+    let output = Command::new(&exe)
+        .env("LD_LIBRARY_PATH", &lib_dir)
+        .env("DYLD_LIBRARY_PATH", &lib_dir)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "C harness failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}